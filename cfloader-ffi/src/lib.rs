@@ -1,5 +1,3 @@
-use cfloader::{CFLoader, Bllink};
-
 pub fn hello() {
     // CFLoader::new(bllink)
 }
\ No newline at end of file
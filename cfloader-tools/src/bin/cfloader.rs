@@ -2,7 +2,7 @@ use std::path::PathBuf;
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
-use cfloader::{Bllink, CFLoader};
+use cfloader::{Bllink, CFLoader, FlashOptions, LinkConfig, VerifyMode, bootloader};
 use indicatif::{ProgressBar, ProgressStyle};
 use tokio::fs;
 
@@ -26,6 +26,62 @@ enum Commands {
         /// Platform to flash (stm32 or nrf51)
         #[arg(short, long)]
         platform: String,
+        /// Only write the pages that differ from the current flash content
+        #[arg(long)]
+        delta: bool,
+        /// Write the first page, holding the vector table, last so that an interrupted flashing
+        /// leaves the Crazyflie in bootloader mode
+        #[arg(long)]
+        safe: bool,
+        /// Load the next pages while the previous ones are written, for bootloaders answering
+        /// during flash writes
+        #[arg(long)]
+        double_buffer: bool,
+        /// Load identical pages, like erased padding, once and write them from the same buffer page
+        #[arg(long)]
+        dedupe: bool,
+        /// Save the current firmware in this directory before flashing and restore it on failure
+        #[arg(long)]
+        backup: Option<PathBuf>,
+        /// Print the flash operations that would be done without writing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Flash the STM32 and nRF51 binary files of a platform update together
+    FlashAll {
+        /// STM32 binary file
+        #[arg(long)]
+        stm32: PathBuf,
+        /// nRF51 binary file
+        #[arg(long)]
+        nrf51: PathBuf,
+        /// Only write the pages that differ from the current flash content
+        #[arg(long)]
+        delta: bool,
+        /// Save the current firmwares in this directory before flashing and restore them on failure
+        #[arg(long)]
+        backup: Option<PathBuf>,
+    },
+    /// Flash back a firmware backup
+    Restore {
+        /// Backup file made by the flash command
+        #[arg(short, long)]
+        file: PathBuf,
+        /// Platform to restore (stm32 or nrf51)
+        #[arg(short, long)]
+        platform: String,
+    },
+    /// Compare the flash of a platform with a binary file
+    Verify {
+        /// Binary file to compare with
+        #[arg(short, long)]
+        file: PathBuf,
+        /// Platform to verify (stm32 or nrf51)
+        #[arg(short, long)]
+        platform: String,
+        /// Only read back one packet out of 8
+        #[arg(short, long)]
+        quick: bool,
     },
 }
 
@@ -34,7 +90,7 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
 
     // Initialize Bllink (will open Crazyradio internally)
-    let bllink = Bllink::new(None).await?;
+    let bllink = Bllink::new(None, LinkConfig::default()).await?;
 
     match &cli.command {
         Commands::Info => {
@@ -54,6 +110,8 @@ async fn main() -> Result<()> {
             println!("  Flash pages: {}", stm32_info.n_flash_page());
             println!("  Flash start: {}", stm32_info.flash_start());
             println!("  Protocol version: {}", stm32_info.version());
+            let payload_size = cfloader.payload_size(bootloader::TARGET_STM32)?;
+            println!("  Payload: {} bytes per load, {} bytes per buffer read, {} bytes per flash read", payload_size.load, payload_size.read, payload_size.flash_read);
             
             // Get and display nRF51 info
             let nrf51_info = cfloader.nrf51_info();
@@ -63,8 +121,10 @@ async fn main() -> Result<()> {
             println!("  Flash pages: {}", nrf51_info.n_flash_page());
             println!("  Flash start: {}", nrf51_info.flash_start());
             println!("  Protocol version: {}", nrf51_info.version());
+            let payload_size = cfloader.payload_size(bootloader::TARGET_NRF51)?;
+            println!("  Payload: {} bytes per load, {} bytes per buffer read, {} bytes per flash read", payload_size.load, payload_size.read, payload_size.flash_read);
         }
-        Commands::Flash { file, platform } => {
+        Commands::Flash { file, platform, delta, safe, double_buffer, dedupe, backup, dry_run } => {
             println!("Flashing {} to {} platform...", file.display(), platform);
             
             // Read the binary file
@@ -74,6 +134,33 @@ async fn main() -> Result<()> {
             // Initialize CFLoader
             let mut cfloader = CFLoader::new(bllink).await?;
             
+            let options = FlashOptions {
+                delta: *delta,
+                safe_order: *safe,
+                double_buffer: *double_buffer,
+                dedupe_pages: *dedupe,
+                backup: backup.clone(),
+                ..Default::default()
+            };
+
+            if *dry_run {
+                let (target, info) = match platform.to_lowercase().as_str() {
+                    "stm32" => (bootloader::TARGET_STM32, cfloader.stm32_info()),
+                    "nrf51" => (bootloader::TARGET_NRF51, cfloader.nrf51_info()),
+                    _ => {
+                        return Err(anyhow::anyhow!(
+                            "Invalid platform '{}'. Use 'stm32' or 'nrf51'",
+                            platform
+                        ));
+                    }
+                };
+                let start_address = info.flash_start() as u32 * info.page_size() as u32;
+
+                let plan = cfloader.plan_flash(target, start_address, &firmware_data, &options).await?;
+                print!("{}", plan);
+                return Ok(());
+            }
+
             // Create progress bar
             let progress_bar = ProgressBar::new(firmware_data.len() as u64);
             progress_bar.set_style(
@@ -96,7 +183,7 @@ async fn main() -> Result<()> {
                         pb.set_position(bytes_written as u64);
                     };
                     
-                    cfloader.flash_stm32_with_progress(start_address, &firmware_data, Some(progress_callback)).await?;
+                    cfloader.flash_image_with_options(bootloader::TARGET_STM32, start_address, &firmware_data, &options, Some(progress_callback)).await?;
                     progress_bar.finish_with_message("STM32F405 flashed successfully!");
                 }
                 "nrf51" => {
@@ -110,7 +197,7 @@ async fn main() -> Result<()> {
                         pb.set_position(bytes_written as u64);
                     };
                     
-                    cfloader.flash_nrf51_with_progress(start_address, &firmware_data, Some(progress_callback)).await?;
+                    cfloader.flash_image_with_options(bootloader::TARGET_NRF51, start_address, &firmware_data, &options, Some(progress_callback)).await?;
                     progress_bar.finish_with_message("nRF51822 flashed successfully!");
                 }
                 _ => {
@@ -121,6 +208,83 @@ async fn main() -> Result<()> {
                 }
             }
         }
+        Commands::FlashAll { stm32, nrf51, delta, backup } => {
+            let stm32_data = fs::read(stm32).await?;
+            let nrf51_data = fs::read(nrf51).await?;
+            println!("Read {} bytes from {}", stm32_data.len(), stm32.display());
+            println!("Read {} bytes from {}", nrf51_data.len(), nrf51.display());
+
+            let mut cfloader = CFLoader::new(bllink).await?;
+
+            let options = FlashOptions { delta: *delta, backup: backup.clone(), ..Default::default() };
+
+            let stm32_info = cfloader.stm32_info();
+            let stm32_address = stm32_info.flash_start() as u32 * stm32_info.page_size() as u32;
+            let nrf51_info = cfloader.nrf51_info();
+            let nrf51_address = nrf51_info.flash_start() as u32 * nrf51_info.page_size() as u32;
+            println!("Flashing STM32F405 at address 0x{:08X} and nRF51822 at address 0x{:08X}...", stm32_address, nrf51_address);
+
+            let progress_bar = ProgressBar::new((stm32_data.len() + nrf51_data.len()) as u64);
+            progress_bar.set_style(
+                ProgressStyle::default_bar()
+                    .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+                    .unwrap()
+                    .progress_chars("#>-"),
+            );
+
+            let pb = progress_bar.clone();
+            let progress_callback = move |bytes_written: usize, _total_bytes: usize| {
+                pb.set_position(bytes_written as u64);
+            };
+
+            cfloader.flash_platform(stm32_address, &stm32_data, nrf51_address, &nrf51_data, &options, Some(progress_callback)).await?;
+            progress_bar.finish_with_message("Platform flashed successfully!");
+        }
+        Commands::Restore { file, platform } => {
+            let target = match platform.to_lowercase().as_str() {
+                "stm32" => bootloader::TARGET_STM32,
+                "nrf51" => bootloader::TARGET_NRF51,
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "Invalid platform '{}'. Use 'stm32' or 'nrf51'",
+                        platform
+                    ));
+                }
+            };
+
+            let mut cfloader = CFLoader::new(bllink).await?;
+
+            println!("Restoring {} to {} platform...", file.display(), platform);
+            cfloader.restore(target, file).await?;
+            println!("Firmware restored");
+        }
+        Commands::Verify { file, platform, quick } => {
+            let firmware_data = fs::read(file).await?;
+
+            let mut cfloader = CFLoader::new(bllink).await?;
+
+            let (target, info) = match platform.to_lowercase().as_str() {
+                "stm32" => (bootloader::TARGET_STM32, cfloader.stm32_info()),
+                "nrf51" => (bootloader::TARGET_NRF51, cfloader.nrf51_info()),
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "Invalid platform '{}'. Use 'stm32' or 'nrf51'",
+                        platform
+                    ));
+                }
+            };
+            let start_address = info.flash_start() as u32 * info.page_size() as u32;
+            let mode = if *quick { VerifyMode::Sampled(8) } else { VerifyMode::Full };
+
+            println!("Verifying {} against {} platform...", file.display(), platform);
+            let report = cfloader.verify_with_mode(target, start_address, &firmware_data, mode).await?;
+            print!("{}", report);
+
+            if !report.is_ok() {
+                return Err(anyhow::anyhow!("Flash content differs from {}", file.display()));
+            }
+            println!("Verification passed");
+        }
     }
 
     Ok(())
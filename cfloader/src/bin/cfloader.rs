@@ -1,8 +1,8 @@
-use cfloader::{Bllink, CFLoader};
+use cfloader::{Bllink, CFLoader, LinkConfig};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let bllink = Bllink::new(None).await.expect("Failed to create Bllink");
+    let bllink = Bllink::new(None, LinkConfig::default()).await.expect("Failed to create Bllink");
     let mut cfloader = CFLoader::new(bllink).await.expect("Failed to create CFLoader");
     
     // Print bootloader information
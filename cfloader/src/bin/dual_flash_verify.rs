@@ -1,9 +1,8 @@
-use cfloader::{Bllink, CFLoader, bootloader};
+use cfloader::{Bllink, CFLoader, LinkConfig, bootloader};
 use std::time::Instant;
 use anyhow::Result;
 use std::env;
 use std::fs;
-use std::io::{self, Write};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -56,7 +55,7 @@ async fn main() -> Result<()> {
     
     // Initialize CFLoader
     println!("\nInitializing radio and bootloaders...");
-    let bllink = match Bllink::new(None).await {
+    let bllink = match Bllink::new(None, LinkConfig::default()).await {
         Ok(bllink) => bllink,
         Err(e) => {
             println!("❌ Failed to initialize radio: {}", e);
@@ -178,66 +177,8 @@ async fn main() -> Result<()> {
 }
 
 async fn verify_flash(cfloader: &mut CFLoader, target: u8, start_address: u32, bin_data: &[u8], target_name: &str) -> Result<bool> {
-    const CHUNK_SIZE: u32 = 256; // Read in 256-byte chunks for efficiency
-    let total_bytes = bin_data.len() as u32;
-    let mut bytes_verified = 0u32;
-    
-    let start_time = Instant::now();
-    
-    while bytes_verified < total_bytes {
-        let remaining = total_bytes - bytes_verified;
-        let chunk_size = remaining.min(CHUNK_SIZE);
-        let current_address = start_address + bytes_verified;
-        
-        // Show progress every 10%
-        let progress = (bytes_verified as f64 / total_bytes as f64) * 100.0;
-        if bytes_verified % (total_bytes / 10).max(1) == 0 || bytes_verified == 0 {
-            print!("\r   {} progress: {:.1}%", target_name, progress);
-            io::stdout().flush().unwrap();
-        }
-        
-        // Read flash chunk
-        let flash_data = match target {
-            bootloader::TARGET_STM32 => {
-                cfloader.read_stm32_flash(current_address, chunk_size).await?
-            }
-            bootloader::TARGET_NRF51 => {
-                cfloader.read_nrf51_flash(current_address, chunk_size).await?
-            }
-            _ => return Err(anyhow::anyhow!("Invalid target")),
-        };
-        
-        // Compare with binary data
-        let bin_chunk = &bin_data[bytes_verified as usize..(bytes_verified + chunk_size) as usize];
-        let compare_len = flash_data.len().min(bin_chunk.len());
-        
-        // Compare byte by byte
-        for (i, (&flash_byte, &bin_byte)) in flash_data[..compare_len].iter().zip(bin_chunk[..compare_len].iter()).enumerate() {
-            if flash_byte != bin_byte {
-                print!("\r"); // Clear progress line
-                println!("   ❌ MISMATCH at address 0x{:08X} (offset {})", 
-                         current_address + i as u32, bytes_verified + i as u32);
-                println!("      Flash: 0x{:02X}, Binary: 0x{:02X}", flash_byte, bin_byte);
-                
-                // Show context around the mismatch
-                let context_start = i.saturating_sub(8);
-                let context_end = (i + 16).min(compare_len);
-                println!("      Flash context:  {:02X?}", &flash_data[context_start..context_end]);
-                println!("      Binary context: {:02X?}", &bin_chunk[context_start..context_end]);
-                
-                return Ok(false);
-            }
-        }
-        
-        bytes_verified += compare_len as u32;
-        
-        // Small delay to prevent overwhelming the bootloader
-        tokio::time::sleep(tokio::time::Duration::from_millis(5)).await;
-    }
-    
-    // Complete progress line
-    print!("\r   {} progress: 100.0%", target_name);
-    println!(" - {} bytes verified in {:.2}ms", total_bytes, start_time.elapsed().as_millis());
-    
-    Ok(true)
-}
\ No newline at end of file
+    let report = cfloader.verify(target, start_address, bin_data).await?;
+    print!("   {} {}", target_name, report);
+
+    Ok(report.is_ok())
+}
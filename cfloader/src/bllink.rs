@@ -4,209 +4,426 @@
 // We will be using is as a half-duplex link in this case, only sending or receiving at a time
 
 use crazyradio::{Crazyradio, SharedCrazyradio};
-use std::time::Duration;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
 
-pub struct Bllink {
+use crate::config::{CommandClass, LinkConfig, RetryPolicy};
+use crate::error::{Error, Result};
+use crate::link::{Ack, Link, PacketLink};
+use crate::timing::LinkTiming;
+
+/// Crazyradio packet link to the bootloader radio address and channel
+pub struct RadioLink {
     radio: SharedCrazyradio,
     address: [u8; 5],
     channel: crazyradio::Channel,
 }
 
+pub struct Bllink<P: PacketLink = RadioLink> {
+    link: P,
+    // One estimator per target and command class: the STM32 is reached through the nRF51 and
+    // a flash write is not answered as fast as an info query
+    timing: HashMap<(u8, CommandClass), LinkTiming>,
+    config: LinkConfig,
+}
+
 const DEFAULT_ADDRESS: [u8; 5] = [0xE7, 0xE7, 0xE7, 0xE7, 0xE7];
 const BOOTLOADER_CHANNEL: u8 = 0; // Bootloader channel
-const MAX_RETRIES: usize = 10; // Maximum number of retries for packet transmission
 
-impl Bllink {
-    pub async fn new(address: Option<&[u8; 5]>) -> anyhow::Result<Self> {
+impl RadioLink {
+    pub async fn new(address: Option<&[u8; 5]>) -> Result<Self> {
         let address = address.unwrap_or(&DEFAULT_ADDRESS);
 
-        let radio = Crazyradio::open_first_async().await?;
+        let radio = Crazyradio::open_first_async().await.map_err(Error::NoRadio)?;
         let radio = SharedCrazyradio::new(radio);
 
+        Ok(RadioLink { radio, channel: crazyradio::Channel::from_number(BOOTLOADER_CHANNEL).unwrap(), address: *address })
+    }
+}
+
+impl PacketLink for RadioLink {
+    async fn send_packet(&mut self, data: &[u8]) -> Result<Ack> {
+        let (ack, payload) = self.radio.send_packet_async(self.channel, self.address, data.to_vec()).await
+            .map_err(Error::Radio)?;
+        Ok(Ack { received: ack.received, payload })
+    }
+}
+
+impl Bllink {
+    pub async fn new(address: Option<&[u8; 5]>, config: LinkConfig) -> Result<Self> {
+        let link = RadioLink::new(address).await?;
+
         // TODO: Check connectivity by sending a ping or similar
 
-        Ok(Bllink { radio, channel: crazyradio::Channel::from_number(BOOTLOADER_CHANNEL).unwrap(), address: *address })
+        Ok(Bllink::with_packet_link(link).with_config(config))
     }
+}
 
+impl<P: PacketLink> Bllink<P> {
+    /// Create a bootloader link on top of any packet link (simulator, recorded session, ...)
+    pub fn with_packet_link(link: P) -> Self {
+        Bllink { link, timing: HashMap::new(), config: LinkConfig::default() }
+    }
 
-    // Send a packet as request, expect one packet as response
-    pub async fn request(&mut self, data: &[u8], timeout_duration: Duration) -> anyhow::Result<Vec<u8>> {
-        for attempt in 0..MAX_RETRIES {
-            match self.try_request(data, timeout_duration).await {
-                Ok(response) => return Ok(response),
-                Err(e) => {
-                    if attempt == MAX_RETRIES - 1 {
-                        return Err(anyhow::anyhow!(
-                            "Failed to get response after {} attempts: {}", 
-                            MAX_RETRIES, e
-                        ));
-                    }
-                    // Log retry attempt if desired
-                    //eprintln!("Request attempt {} failed: {}, retrying...", attempt + 1, e);
-                }
-            }
-        }
-        unreachable!()
+    /// Use other retry and timeout policies than the default ones
+    pub fn with_config(self, config: LinkConfig) -> Self {
+        Bllink { config, ..self }
     }
 
-    // Send a packet as request, expect one packet as response. The first n bytes of the response must match the request
-    pub async fn request_match_response(&mut self, data: &[u8], match_length: usize, timeout_duration: Duration) -> anyhow::Result<Vec<u8>> {
-        for attempt in 0..MAX_RETRIES {
-            match self.try_request_match_response(data, match_length, timeout_duration).await {
-                Ok(response) => return Ok(response),
-                Err(e) => {
-                    if attempt == MAX_RETRIES - 1 {
-                        return Err(anyhow::anyhow!(
-                            "Failed to get matching response after {} attempts: {}", 
-                            MAX_RETRIES, e
-                        ));
-                    }
-                    // Log retry attempt if desired
-                    //eprintln!("Request match attempt {} failed: {}, retrying...", attempt + 1, e);
-                }
-            }
-        }
-        unreachable!()
+    /// Get the timing measured on the link for a class of commands sent to a target, used for
+    /// their timeouts and delays
+    ///
+    /// None until a command of this class has been sent to the target.
+    pub fn timing(&self, target: u8, class: CommandClass) -> Option<&LinkTiming> {
+        self.timing.get(&(target, class))
+    }
+
+    // Timing of the exchanges of a request
+    fn timing_mut(&mut self, request: &[u8]) -> &mut LinkTiming {
+        let target = request.get(1).copied().unwrap_or_default();
+        self.timing.entry((target, CommandClass::of(request))).or_default()
+    }
+
+    /// Get the underlying packet link
+    pub fn packet_link(&self) -> &P {
+        &self.link
+    }
+
+    /// Get the underlying packet link mutably
+    pub fn packet_link_mut(&mut self) -> &mut P {
+        &mut self.link
+    }
+
+    // Send one packet of the exchanges of `request` and record if it has been acknowledged
+    async fn send_packet(&mut self, data: &[u8], request: &[u8]) -> Result<Ack> {
+        let ack = self.link.send_packet(data).await?;
+        self.timing_mut(request).record_ack(ack.received);
+        Ok(ack)
     }
 
     // Internal method to try a single request with partial response matching
-    async fn try_request_match_response(&mut self, data: &[u8], match_length: usize, timeout_duration: Duration) -> anyhow::Result<Vec<u8>> {
+    //
+    // The request is sent until acknowledged, for up to `ack_timeout`, then the response is
+    // awaited for `timeout_duration`.
+    async fn try_request_match_response(&mut self, data: &[u8], match_length: usize, ack_timeout: Duration, timeout_duration: Duration) -> Result<Vec<u8>> {
         let start_time = std::time::Instant::now();
         let mut answer = Vec::new();
         let mut got_initial_ack = false;
+        let mut sent_at = start_time;
         
         // Validate match_length
         if match_length > data.len() {
-            return Err(anyhow::anyhow!("match_length {} cannot be greater than data length {}", match_length, data.len()));
+            return Err(Error::InvalidArgument(format!("match_length {} cannot be greater than data length {}", match_length, data.len())));
         }
         
         let match_data = &data[..match_length];
         
-        // First, send the initial request and wait for ACK within timeout window
-        while start_time.elapsed() < timeout_duration && !got_initial_ack {
-            let (ack, response) = self.radio.send_packet_async(self.channel, self.address, data.to_vec()).await
-                .map_err(|e| anyhow::anyhow!("Radio error during initial send: {}", e))?;
+        // First, send the initial request and wait for ACK, longer under interference
+        while start_time.elapsed() < ack_timeout && !got_initial_ack {
+            sent_at = Instant::now();
+            let ack = self.send_packet(data, data).await?;
 
             if ack.received {
                 got_initial_ack = true;
-                answer = response;
+                answer = ack.payload;
             } else {
-                // Short delay before retry
-                tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+                sleep(self.timing_mut(data).retry_delay()).await;
             }
         }
         
         if !got_initial_ack {
-            return Err(anyhow::anyhow!("Timeout: No ACK received for initial packet within {:?}", timeout_duration));
+            return Err(Error::NoAck { timeout: ack_timeout });
         }
 
         // Keep polling for valid response with remaining timeout
-        while start_time.elapsed() < timeout_duration && (answer.len() < match_length || !answer[..match_length].eq(match_data)) {
-            let (new_ack, new_answer) = self.radio.send_packet_async(self.channel, self.address, vec![0xff]).await
-                .map_err(|e| anyhow::anyhow!("Radio error during polling: {}", e))?;
+        while sent_at.elapsed() < timeout_duration && (answer.len() < match_length || !answer[..match_length].eq(match_data)) {
+            let new_ack = self.send_packet(&[0xff], data).await?;
 
             if new_ack.received {
-                answer = new_answer;
+                answer = new_ack.payload;
             }
             
-            // Short delay before next poll
-            tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+            sleep(self.timing_mut(data).poll_delay(sent_at.elapsed())).await;
         }
         
         if answer.len() < match_length || !answer[..match_length].eq(match_data) {
-            return Err(anyhow::anyhow!(
-                "Timeout: No valid response received within {:?}. Expected first {} bytes to match {:02X?}, got {:02X?}", 
-                timeout_duration, match_length, match_data, 
-                if answer.len() >= match_length { &answer[..match_length] } else { &answer }
-            ));
+            return Err(Error::ResponseTimeout { timeout: timeout_duration });
+        }
+
+        // Only the full echo of the request surely answers it, not an earlier command
+        if answer.starts_with(data) {
+            self.timing_mut(data).record_round_trip(sent_at.elapsed());
         }
 
         Ok(answer)
     }
 
-    // Internal method to try a single request with timeout
-    async fn try_request(&mut self, data: &[u8], timeout_duration: Duration) -> anyhow::Result<Vec<u8>> {
+    // Internal method to try a single request with timeout, as try_request_match_response
+    async fn try_request(&mut self, data: &[u8], ack_timeout: Duration, timeout_duration: Duration) -> Result<Vec<u8>> {
         let start_time = std::time::Instant::now();
         let mut answer = Vec::new();
         let mut got_initial_ack = false;
+        let mut sent_at = start_time;
         
-        // First, send the initial request and wait for ACK within timeout window
-        while start_time.elapsed() < timeout_duration && !got_initial_ack {
-            let (ack, response) = self.radio.send_packet_async(self.channel, self.address, data.to_vec()).await
-                .map_err(|e| anyhow::anyhow!("Radio error during initial send: {}", e))?;
+        // First, send the initial request and wait for ACK, longer under interference
+        while start_time.elapsed() < ack_timeout && !got_initial_ack {
+            sent_at = Instant::now();
+            let ack = self.send_packet(data, data).await?;
 
             if ack.received {
                 got_initial_ack = true;
-                answer = response;
+                answer = ack.payload;
             } else {
-                // Short delay before retry
-                tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+                sleep(self.timing_mut(data).retry_delay()).await;
             }
         }
         
         if !got_initial_ack {
-            return Err(anyhow::anyhow!("Timeout: No ACK received for initial packet within {:?}", timeout_duration));
+            return Err(Error::NoAck { timeout: ack_timeout });
         }
 
         // Keep polling for valid response with remaining timeout
-        while start_time.elapsed() < timeout_duration && !answer.starts_with(data) {
-            let (new_ack, new_answer) = self.radio.send_packet_async(self.channel, self.address, vec![0xff]).await
-                .map_err(|e| anyhow::anyhow!("Radio error during polling: {}", e))?;
+        while sent_at.elapsed() < timeout_duration && !answer.starts_with(data) {
+            let new_ack = self.send_packet(&[0xff], data).await?;
 
             if new_ack.received {
-                answer = new_answer;
+                answer = new_ack.payload;
             }
             
-            // Short delay before next poll
-            tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+            sleep(self.timing_mut(data).poll_delay(sent_at.elapsed())).await;
         }
         
         if !answer.starts_with(data) {
-            return Err(anyhow::anyhow!("Timeout: No valid response received within {:?}", timeout_duration));
+            return Err(Error::ResponseTimeout { timeout: timeout_duration });
         }
 
+        self.timing_mut(data).record_round_trip(sent_at.elapsed());
         Ok(answer)
     }
 
-    // Send a packet as request, expect no response
-    pub async fn send(&mut self, data: &[u8]) -> anyhow::Result<()> {
-        self.send_with_timeout(data, Duration::from_millis(1000)).await
-    }
-
-    // Send a packet with timeout and retry logic, expect no response
-    pub async fn send_with_timeout(&mut self, data: &[u8], timeout_duration: Duration) -> anyhow::Result<()> {
-        for attempt in 0..MAX_RETRIES {
-            match self.try_send(data, timeout_duration).await {
-                Ok(_) => return Ok(()),
-                Err(e) => {
-                    if attempt == MAX_RETRIES - 1 {
-                        return Err(anyhow::anyhow!(
-                            "Failed to send packet after {} attempts: {}", 
-                            MAX_RETRIES, e
-                        ));
-                    }
-                }
+    // Send a packet with the timeout and retries of `policy`, expect no response
+    pub async fn send_with_policy(&mut self, data: &[u8], policy: &RetryPolicy) -> Result<()> {
+        for attempt in 0..=policy.retries {
+            match self.try_send(data, policy.timeout).await {
+                Err(Error::NoAck { .. }) if attempt < policy.retries => {}
+                result => return result,
             }
         }
         unreachable!()
     }
 
     // Internal method to try a single send with timeout
-    async fn try_send(&mut self, data: &[u8], timeout_duration: Duration) -> anyhow::Result<()> {
+    async fn try_send(&mut self, data: &[u8], timeout_duration: Duration) -> Result<()> {
         let start_time = std::time::Instant::now();
         
         while start_time.elapsed() < timeout_duration {
-            let (ack, _answer) = self.radio.send_packet_async(self.channel, self.address, data.to_vec()).await
-                .map_err(|e| anyhow::anyhow!("Radio error during send: {}", e))?;
+            let ack = self.send_packet(data, data).await?;
 
             if ack.received {
                 return Ok(());
             }
             
-            // Short delay before retry
-            tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+            sleep(self.timing_mut(data).retry_delay()).await;
         }
         
-        Err(anyhow::anyhow!("Timeout: No ACK received within {:?}", timeout_duration))
+        Err(Error::NoAck { timeout: timeout_duration })
+    }
+}
+
+impl<P: PacketLink> Link for Bllink<P> {
+    fn config(&self) -> &LinkConfig {
+        &self.config
+    }
+
+    fn set_config(&mut self, config: LinkConfig) {
+        self.config = config;
+    }
+
+    // Send a packet as request, expect one packet as response
+    async fn request(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let policy = self.config.policy(CommandClass::of(data));
+        for attempt in 0..=policy.retries {
+            let timeout = self.timing_mut(data).response_timeout(&policy);
+            match self.try_request(data, policy.timeout, timeout).await {
+                // A request not acknowledged or not answered in time is sent again
+                Err(Error::ResponseTimeout { .. }) if attempt < policy.retries => self.timing_mut(data).record_timeout(),
+                Err(Error::NoAck { .. }) if attempt < policy.retries => {}
+                result => return result,
+            }
+        }
+        unreachable!()
+    }
+
+    // Send a packet as request, expect one packet as response. The first n bytes of the response must match the request
+    async fn request_match_response(&mut self, data: &[u8], match_length: usize) -> Result<Vec<u8>> {
+        self.request_accepted_response(data, match_length, |_| true).await
     }
-}
\ No newline at end of file
+
+    // Send a packet as request, expect one packet as response matching the request and accepted by `accept`
+    async fn request_accepted_response<F>(&mut self, data: &[u8], match_length: usize, accept: F) -> Result<Vec<u8>>
+    where
+        F: Fn(&[u8]) -> bool + Send,
+    {
+        let policy = self.config.policy(CommandClass::of(data));
+        for attempt in 0..=policy.retries {
+            let timeout = self.timing_mut(data).response_timeout(&policy);
+            match self.try_request_match_response(data, match_length, policy.timeout, timeout).await {
+                // A request not acknowledged, not answered in time or answered with a rejected
+                // response is sent again
+                Err(Error::ResponseTimeout { .. }) if attempt < policy.retries => self.timing_mut(data).record_timeout(),
+                Err(Error::NoAck { .. }) if attempt < policy.retries => {}
+                Ok(response) if !accept(&response) && attempt < policy.retries => {}
+                result => return result,
+            }
+        }
+        unreachable!()
+    }
+
+    fn max_packet_size(&self) -> usize {
+        self.link.max_packet_size()
+    }
+
+    // Send a packet as request, expect no response
+    async fn send(&mut self, data: &[u8]) -> Result<()> {
+        let policy = self.config.policy(CommandClass::of(data));
+        self.send_with_policy(data, &policy).await
+    }
+
+    // Send a packet a single time, the caller decides what to do if it is not acknowledged
+    async fn send_once(&mut self, data: &[u8]) -> Result<bool> {
+        Ok(self.send_packet(data, data).await?.received)
+    }
+
+    // Send packets back-to-back, then send again the ones not acknowledged
+    //
+    // A packet that is not acknowledged does not block the following ones: it is sent again in
+    // the next round, without waiting. The packets fail when none is acknowledged during all
+    // the attempts of their policy.
+    async fn send_pipelined(&mut self, packets: &[Vec<u8>]) -> Result<()> {
+        let Some(first) = packets.first() else {
+            return Ok(());
+        };
+        let send_timeout = self.config.policy(CommandClass::of(first)).total_timeout();
+        let mut pending: Vec<&Vec<u8>> = packets.iter().collect();
+        let mut last_ack = Instant::now();
+
+        while !pending.is_empty() {
+            let mut lost = Vec::new();
+            for &packet in &pending {
+                if self.send_packet(packet, packet).await?.received {
+                    last_ack = Instant::now();
+                } else {
+                    lost.push(packet);
+                }
+            }
+            pending = lost;
+
+            if !pending.is_empty() {
+                if last_ack.elapsed() >= send_timeout {
+                    return Err(Error::NoAck { timeout: send_timeout });
+                }
+                sleep(self.timing_mut(first).retry_delay()).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Send requests while the responses of the previous ones are received
+    //
+    // Each packet carries the next request and its acknowledgement the response to an earlier
+    // one, instead of polling with empty packets. Up to `pipeline_depth` requests wait for their
+    // response, a request not answered within the timeout is sent again.
+    async fn request_pipelined(&mut self, requests: &[Vec<u8>], match_length: usize) -> Result<Vec<Vec<u8>>> {
+        if requests.iter().any(|request| match_length > request.len()) {
+            return Err(Error::InvalidArgument(format!("match_length {} cannot be greater than request length", match_length)));
+        }
+        let Some(first) = requests.first() else {
+            return Ok(Vec::new());
+        };
+        let policy = self.config.policy(CommandClass::of(first));
+        let send_timeout = policy.total_timeout();
+
+        let mut responses: Vec<Option<Vec<u8>>> = vec![None; requests.len()];
+        let mut remaining = requests.len();
+        let mut attempts = vec![0; requests.len()];
+        // Requests to send, requests sent with their send time
+        let mut to_send: VecDeque<usize> = (0..requests.len()).collect();
+        let mut in_flight: VecDeque<(usize, Instant)> = VecDeque::new();
+        let mut last_ack = Instant::now();
+
+        while remaining > 0 {
+            let timeout = self.timing_mut(first).response_timeout(&policy);
+
+            // Requests not answered in time are sent again first
+            while let Some(&(index, sent)) = in_flight.front() {
+                if sent.elapsed() < timeout {
+                    break;
+                }
+                self.timing_mut(first).record_timeout();
+                in_flight.pop_front();
+                to_send.push_front(index);
+            }
+
+            // Only the requests acknowledged by the target count as attempts, the lost ones are
+            // sent again until the link fails
+            let index = if in_flight.len() < self.config.pipeline_depth.max(1) { to_send.pop_front() } else { None };
+            if let Some(index) = index
+                && attempts[index] > policy.retries
+            {
+                return Err(Error::ResponseTimeout { timeout });
+            }
+
+            let ack = self.send_packet(index.map_or(&[0xff][..], |index| &requests[index]), first).await?;
+            if !ack.received {
+                if last_ack.elapsed() >= send_timeout {
+                    return Err(Error::NoAck { timeout: send_timeout });
+                }
+                if let Some(index) = index {
+                    to_send.push_front(index);
+                }
+                sleep(self.timing_mut(first).retry_delay()).await;
+                continue;
+            }
+
+            last_ack = Instant::now();
+            if let Some(index) = index {
+                attempts[index] += 1;
+                in_flight.push_back((index, last_ack));
+            }
+
+            // A response can answer a request waiting to be sent again after its timeout
+            let payload = ack.payload;
+            if payload.len() < match_length {
+                continue;
+            }
+            let matches = |index: usize| requests[index][..match_length] == payload[..match_length];
+            let answered = if let Some(position) = in_flight.iter().position(|&(index, _)| matches(index)) {
+                let (index, sent) = in_flight.remove(position).unwrap();
+                if attempts[index] == 1 {
+                    self.timing_mut(first).record_round_trip(sent.elapsed());
+                }
+                Some(index)
+            } else if let Some(position) = to_send.iter().position(|&index| attempts[index] > 0 && matches(index)) {
+                to_send.remove(position)
+            } else {
+                // Stale response of a previous request
+                None
+            };
+
+            if let Some(index) = answered {
+                responses[index] = Some(payload);
+                remaining -= 1;
+            }
+        }
+
+        Ok(responses.into_iter().flatten().collect())
+    }
+}
+
+// Sleep before the next packet, packets are sent back-to-back without delay
+async fn sleep(delay: Duration) {
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+}
@@ -1,48 +1,77 @@
 // Interface to one bootloader state machine
 // Crazyflie 2.x platform has 2 such bootloader, one in the nRF and one in the STM32
 
-use std::time::Duration;
-
-use bllink::Bllink;
-
-use crate::{bllink, packets::*};
-
-// Bootloader command constants
-const CMD_GET_INFO: u8 = 0x10;
-const CMD_SET_ADDRESS: u8 = 0x11;
-const CMD_GET_MAPPING: u8 = 0x12;
-const CMD_LOAD_BUFFER: u8 = 0x14;
-const CMD_READ_BUFFER: u8 = 0x15;
-const CMD_WRITE_FLASH: u8 = 0x18;
-const CMD_FLASH_STATUS: u8 = 0x19;
-const CMD_READ_FLASH: u8 = 0x1C;
-const CMD_RESET_INIT: u8 = 0xFF;
-const CMD_RESET: u8 = 0xF0;
-const CMD_ALLOFF: u8 = 0x01;
-const CMD_SYSOFF: u8 = 0x02;
-const CMD_SYSON: u8 = 0x03;
-const CMD_GETVBAT: u8 = 0x04;
+use std::time::{Duration, Instant};
+
+use crate::{codec::{Command, Response}, error::{Error, Result}, link::{Link, MAX_RADIO_PACKET_SIZE}, packets::*};
 
 // Bootloader targets
 pub const TARGET_STM32: u8 = 0xFF;
 pub const TARGET_NRF51: u8 = 0xFE;
 
-// Default short timeout for bootloader operations that should return directly
-const SHORT_TIMEOUT: Duration = Duration::from_millis(10);
-// Timeout for flash operation, flash operation can take up to one second to complete
-const FLASH_TIMEOUT: Duration = Duration::from_secs(2);
+// Size of the [0xff, target, cmd, page, address] header of buffer loads and read responses
+pub(crate) const PAYLOAD_HEADER_SIZE: usize = 7;
+
+// First protocol version, the Crazyflie 2.x one, whose bootloaders can take packets larger than
+// a Crazyradio packet. The Crazyflie 1.0 versions before it only use the default payloads.
+const PROBE_MIN_VERSION: u8 = 0x10;
+
+/// Largest data payloads of the buffer load and read commands
+///
+/// The payloads are limited by the packet size of the link and by the bootloader version. The
+/// default fits a 32 bytes Crazyradio packet, [Bootloader::probe_payload_size] finds the actual
+/// limits of a link and bootloader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadSize {
+    /// Data bytes of one LOAD_BUFFER packet
+    pub load: usize,
+    /// Data bytes of one READ_BUFFER response
+    pub read: usize,
+    /// Data bytes of one READ_FLASH response
+    pub flash_read: usize,
+}
+
+impl Default for PayloadSize {
+    fn default() -> Self {
+        let size = MAX_RADIO_PACKET_SIZE - PAYLOAD_HEADER_SIZE;
+        PayloadSize { load: size, read: size, flash_read: size }
+    }
+}
+
+// State of a write command seen from the FLASH_STATUS responses
+enum WriteState {
+    // The bootloader is idle and the command has not been acknowledged: it has either never been
+    // received or been completed with its response lost
+    Idle(FlashStatusResponse),
+    InProgress,
+    Done(FlashWriteResponse),
+}
 
 /// Bootloader interface for Crazyflie 2.x platform
 /// 
 /// The Crazyflie 2.x platform has 2 bootloaders: one in the nRF51822 and one in the STM32F405.
 /// This struct provides a unified interface to communicate with either bootloader.
+#[derive(Debug, Clone, Copy)]
 pub struct Bootloader {
     target: u8,
+    payload_size: PayloadSize,
+    // Page size reported by GET_INFO, asked to the bootloader when unknown
+    page_size: Option<u16>,
 }
 
 impl Bootloader {
     pub fn new(target: u8) -> Self {
-        Bootloader { target }
+        Bootloader { target, payload_size: PayloadSize::default(), page_size: None }
+    }
+
+    /// Use payloads of another size, typically found by [Bootloader::probe_payload_size]
+    pub fn with_payload_size(self, payload_size: PayloadSize) -> Self {
+        Bootloader { payload_size, ..self }
+    }
+
+    /// Use the page size of an info packet instead of asking it again when checking a write
+    pub fn with_info(self, info: &InfoPacket) -> Self {
+        Bootloader { page_size: Some(info.page_size()), ..self }
     }
 
     /// Create a bootloader for the STM32 target (0xFF)
@@ -60,137 +89,384 @@ impl Bootloader {
         self.target
     }
 
-    pub async fn get_info(&self, bllink: &mut Bllink) -> anyhow::Result<InfoPacket> {
-        let get_info_command = vec![0xff, self.target, CMD_GET_INFO];
-        let response = bllink.request(&get_info_command, SHORT_TIMEOUT).await?;
-        Ok(InfoPacket::from_bytes(&response[2..]))
+    /// Largest data payloads used by the buffer load and read commands
+    pub fn payload_size(&self) -> PayloadSize {
+        self.payload_size
     }
 
-    pub async fn set_address(&self, bllink: &mut Bllink, address: &[u8; 5]) -> anyhow::Result<()> {
-        let mut command = vec![0xff, self.target, CMD_SET_ADDRESS];
-        command.extend_from_slice(address);
-        bllink.send(&command).await?;
-        Ok(())
+    // Send a command and decode its response
+    //
+    // A response echoing the command but cut short or corrupted on the way is not an answer:
+    // the command is sent again, like for a lost response, within the same attempts of the link
+    // policy.
+    async fn request<L: Link>(&self, link: &mut L, command: &Command) -> Result<Response> {
+        let target = self.target;
+        let well_formed = move |response: &[u8]| !matches!(Response::decode(target, response), Err(Error::MalformedPacket(_)));
+        let response = link.request_accepted_response(&command.encode(target), command.echo_length(), well_formed).await?;
+        Response::decode(target, &response)
     }
 
-    pub async fn get_mapping(&self, bllink: &mut Bllink) -> anyhow::Result<Vec<u8>> {
-        let command = vec![0xff, self.target, CMD_GET_MAPPING];
-        let response = bllink.request(&command, SHORT_TIMEOUT).await?;
-        // Skip the first byte (command echo) and return the mapping data
-        Ok(response[1..].to_vec())
+    // Send a command that has no response
+    async fn send<L: Link>(&self, link: &mut L, command: &Command) -> Result<()> {
+        link.send(&command.encode(self.target)).await
     }
 
-    pub async fn load_buffer(&self, bllink: &mut Bllink, page: u16, address: u16, data: &[u8]) -> anyhow::Result<()> {
-        if data.len() > 25 {
-            return Err(anyhow::anyhow!("Data too large for buffer load (max 25 bytes)"));
+    pub async fn get_info<L: Link>(&self, link: &mut L) -> Result<InfoPacket> {
+        match self.request(link, &Command::GetInfo).await? {
+            Response::Info(info) => Ok(info),
+            other => Err(other.unexpected("GET_INFO")),
+        }
+    }
+
+    pub async fn set_address<L: Link>(&self, link: &mut L, address: &[u8; 5]) -> Result<()> {
+        self.send(link, &Command::SetAddress(*address)).await
+    }
+
+    /// Get the flash sector mapping, as pairs of (number of sectors, sector size in KB)
+    ///
+    /// Only the mapping is returned. Earlier versions returned the response from its target
+    /// byte, with the target and command echo before the mapping.
+    pub async fn get_mapping<L: Link>(&self, link: &mut L) -> Result<Vec<u8>> {
+        match self.request(link, &Command::GetMapping).await? {
+            Response::Mapping(mapping) => Ok(mapping),
+            other => Err(other.unexpected("GET_MAPPING")),
+        }
+    }
+
+    pub async fn load_buffer<L: Link>(&self, link: &mut L, page: u16, address: u16, data: &[u8]) -> Result<()> {
+        if data.len() > self.payload_size.load {
+            return Err(Error::InvalidArgument(format!("Data too large for buffer load: {} bytes (max {} bytes)", data.len(), self.payload_size.load)));
         }
-        
-        let mut command = vec![0xff, self.target, CMD_LOAD_BUFFER];
-        command.extend_from_slice(&page.to_le_bytes());
-        command.extend_from_slice(&address.to_le_bytes());
-        command.extend_from_slice(data);
         
         // Simple send with ACK - no detailed response validation since it's just an ACK
-        bllink.send(&command).await?;
-        Ok(())
+        self.send(link, &Command::LoadBuffer { page, address, data: data.to_vec() }).await
     }
 
-    pub async fn read_buffer(&self, bllink: &mut Bllink, page: u16, address: u16) -> anyhow::Result<BufferReadPacket> {
-        let mut command = vec![0xff, self.target, CMD_READ_BUFFER];
-        command.extend_from_slice(&page.to_le_bytes());
-        command.extend_from_slice(&address.to_le_bytes());
-        
-        let response = bllink.request(&command, SHORT_TIMEOUT).await?;
-        Ok(BufferReadPacket::from_bytes(&response[2..]))
+    /// Load several `(page, address, data)` segments in the RAM buffer
+    ///
+    /// The LOAD_BUFFER packets are sent back-to-back, only the ones not acknowledged are sent
+    /// again. Loading a segment twice is harmless.
+    pub async fn load_buffer_pipelined<L: Link>(&self, link: &mut L, segments: &[(u16, u16, &[u8])]) -> Result<()> {
+        let mut packets = Vec::with_capacity(segments.len());
+        for &(page, address, data) in segments {
+            if data.len() > self.payload_size.load {
+                return Err(Error::InvalidArgument(format!("Data too large for buffer load: {} bytes (max {} bytes)", data.len(), self.payload_size.load)));
+            }
+            packets.push(Command::LoadBuffer { page, address, data: data.to_vec() }.encode(self.target));
+        }
+
+        link.send_pipelined(&packets).await
     }
 
-    pub async fn write_flash(&self, bllink: &mut Bllink, buffer_page: u16, flash_page: u16, n_pages: u16) -> anyhow::Result<FlashWriteResponse> {
-        let mut command = vec![0xff, self.target, CMD_WRITE_FLASH];
-        command.extend_from_slice(&buffer_page.to_le_bytes());
-        command.extend_from_slice(&flash_page.to_le_bytes());
-        command.extend_from_slice(&n_pages.to_le_bytes());
-        
-        // TODO: When flashing, if the ack is lost, we should send again a flash status request and not a flash
-        //       This is because flash reequest both takes a lot of time and utilize flash endurance of the chip.
-        let response = bllink.request_match_response(&command, 3, FLASH_TIMEOUT).await?;
-        Ok(FlashWriteResponse::from_bytes(&response[2..]))
+    /// Find the largest buffer load and read payloads going through the link and bootloader unchanged
+    ///
+    /// The read sizes are the data lengths of a READ_BUFFER and of a READ_FLASH response, which
+    /// can exceed the Crazyradio payload on a link carrying larger packets. Loads of decreasing
+    /// size, starting from the buffer read size, are then read back from the last buffer page
+    /// until no byte is dropped, with two complementary patterns so that a dropped byte cannot
+    /// match the previous buffer content. A load is never larger than a buffer read so that one
+    /// read checks it. The content of the last buffer page is lost.
+    ///
+    /// Bootloaders older than the Crazyflie 2.x protocol version are not probed, the default
+    /// payloads are returned.
+    pub async fn probe_payload_size<L: Link>(&self, link: &mut L, info: &InfoPacket) -> Result<PayloadSize> {
+        if info.version() < PROBE_MIN_VERSION {
+            return Ok(PayloadSize::default());
+        }
+
+        let max_data = link.max_packet_size().saturating_sub(PAYLOAD_HEADER_SIZE);
+        let page = info.n_buff_page().saturating_sub(1);
+
+        let flash_read = self.read_flash(link, info.flash_start(), 0).await?.data.len().min(max_data);
+        if flash_read == 0 {
+            return Err(Error::MalformedPacket(format!("Empty READ_FLASH response from target 0x{:02X}", self.target)));
+        }
+
+        let read = self.read_buffer(link, page, 0).await?.data.len().min(max_data);
+        'size: for load in (1..=read).rev() {
+            for pattern in [0x55, 0xaa] {
+                // The pattern also depends on the size, a stale response cannot match it
+                let data: Vec<u8> = (0..load).map(|i| pattern ^ (i + load) as u8).collect();
+                self.send(link, &Command::LoadBuffer { page, address: 0, data: data.clone() }).await?;
+                if !self.read_buffer(link, page, 0).await?.data.starts_with(&data) {
+                    continue 'size;
+                }
+            }
+            return Ok(PayloadSize { load, read, flash_read });
+        }
+
+        Err(Error::MalformedPacket(format!("No buffer load of target 0x{:02X} is read back unchanged", self.target)))
     }
 
-    pub async fn flash_status(&self, bllink: &mut Bllink) -> anyhow::Result<FlashStatusResponse> {
-        let command = vec![0xff, self.target, CMD_FLASH_STATUS];
-        let response = bllink.request(&command, SHORT_TIMEOUT).await?;
-        Ok(FlashStatusResponse::from_bytes(&response[2..]))
+    pub async fn read_buffer<L: Link>(&self, link: &mut L, page: u16, address: u16) -> Result<BufferReadPacket> {
+        match self.request(link, &Command::ReadBuffer { page, address }).await? {
+            Response::BufferRead(packet) => Ok(packet),
+            other => Err(other.unexpected("READ_BUFFER")),
+        }
     }
 
-    pub async fn read_flash(&self, bllink: &mut Bllink, page: u16, address: u16) -> anyhow::Result<FlashReadPacket> {
-        let mut command = vec![0xff, self.target, CMD_READ_FLASH];
-        command.extend_from_slice(&page.to_le_bytes());
-        command.extend_from_slice(&address.to_le_bytes());
-        
-        let response = bllink.request(&command, SHORT_TIMEOUT).await?;
-        
-        if response.len() < 2 {
-            return Err(anyhow::anyhow!("Response too short: {} bytes", response.len()));
+    /// Write `n_pages` pages of the RAM buffer to flash
+    ///
+    /// A write takes up to a second per page and wears the flash, so the write command is never sent
+    /// again only because its acknowledgement is lost: FLASH_STATUS is polled until the write response
+    /// arrives. An acknowledged command is never sent again. When neither the command nor the write
+    /// response has been received, an idle bootloader has either never received the command or
+    /// completed it: the written flash pages are compared with the RAM buffer and the command is only
+    /// sent again if they differ, the write fails with [Error::VerifyFailed] if they still differ after
+    /// all the attempts. The timeout and number of attempts are the flash write policy of the link
+    /// configuration, the timeout applies to each page written.
+    pub async fn write_flash<L: Link>(&self, link: &mut L, buffer_page: u16, flash_page: u16, n_pages: u16) -> Result<FlashWriteResponse> {
+        match self.start_write_flash(link, buffer_page, flash_page, n_pages).await? {
+            Some(response) => Ok(response),
+            None => self.wait_write_flash(link, n_pages).await,
         }
-        
-        let flash_packet = FlashReadPacket::from_bytes(&response[2..]);
+    }
+
+    /// Send a write command and return as soon as the bootloader has started it
+    ///
+    /// Returns the write response if the write is already complete, which is always the case with
+    /// a bootloader that does not answer while writing. Otherwise the link can be used, for example
+    /// to load other buffer pages, before waiting for the end of the write with
+    /// [Bootloader::wait_write_flash].
+    pub async fn start_write_flash<L: Link>(&self, link: &mut L, buffer_page: u16, flash_page: u16, n_pages: u16) -> Result<Option<FlashWriteResponse>> {
+        let received = self.send_write_flash(link, buffer_page, flash_page, n_pages).await?;
+        self.follow_write(link, buffer_page, flash_page, n_pages, received, false).await
+    }
+
+    /// Send a write command without waiting for the bootloader to start it
+    ///
+    /// Returns true if the command has been acknowledged: the bootloader is writing and must not
+    /// receive any other command until [Bootloader::wait_write_flash] returns. Otherwise the
+    /// command may or may not have been received, [Bootloader::confirm_write_flash] finds it out
+    /// and sends it again if needed.
+    pub async fn send_write_flash<L: Link>(&self, link: &mut L, buffer_page: u16, flash_page: u16, n_pages: u16) -> Result<bool> {
+        link.send_once(&Command::WriteFlash { buffer_page, flash_page, n_pages }.encode(self.target)).await
+    }
+
+    /// Complete a write sent by [Bootloader::send_write_flash] without acknowledgement
+    ///
+    /// The command is only sent again if the bootloader has never received it, as with
+    /// [Bootloader::write_flash].
+    pub async fn confirm_write_flash<L: Link>(&self, link: &mut L, buffer_page: u16, flash_page: u16, n_pages: u16) -> Result<FlashWriteResponse> {
+        match self.follow_write(link, buffer_page, flash_page, n_pages, false, true).await? {
+            Some(response) => Ok(response),
+            None => self.wait_write_flash(link, n_pages).await,
+        }
+    }
+
+    /// Wait for the end of a write of `n_pages` started by [Bootloader::start_write_flash] or
+    /// [Bootloader::send_write_flash]
+    pub async fn wait_write_flash<L: Link>(&self, link: &mut L, n_pages: u16) -> Result<FlashWriteResponse> {
+        let timeout_duration = write_timeout(link, n_pages);
+        match self.poll_write(link, true, true, timeout_duration).await? {
+            WriteState::Done(response) => Ok(response),
+            _ => Err(Error::ResponseTimeout { timeout: timeout_duration }),
+        }
+    }
+
+    // Follow a write command already sent once, `received` if it has been acknowledged
+    //
+    // Returns None when the bootloader answers while writing and the write is in progress, unless
+    // `wait` is set. The command is sent again when the bootloader is idle with a flash content
+    // differing from the RAM buffer.
+    async fn follow_write<L: Link>(&self, link: &mut L, buffer_page: u16, flash_page: u16, n_pages: u16, mut received: bool, wait: bool) -> Result<Option<FlashWriteResponse>> {
+        let policy = link.config().flash_write;
+        let timeout_duration = write_timeout(link, n_pages);
+        let mut unwritten = flash_page;
+
+        for attempt in 0..=policy.retries {
+            if attempt > 0 {
+                received = self.send_write_flash(link, buffer_page, flash_page, n_pages).await?;
+            }
+            match self.poll_write(link, received, wait, timeout_duration).await? {
+                WriteState::Done(response) => return Ok(Some(response)),
+                WriteState::InProgress => return Ok(None),
+                WriteState::Idle(status) => match self.unwritten_page(link, buffer_page, flash_page, n_pages).await? {
+                    None => return Ok(Some(status)),
+                    Some(page) => unwritten = page,
+                },
+            }
+        }
+
+        // The bootloader has always been idle with a flash differing from the buffer
+        Err(Error::VerifyFailed { target: self.target, pages: vec![unwritten] })
+    }
+
+    // First flash page that does not hold the content of the RAM buffer page it is written from
+    async fn unwritten_page<L: Link>(&self, link: &mut L, buffer_page: u16, flash_page: u16, n_pages: u16) -> Result<Option<u16>> {
+        let page_size = match self.page_size {
+            Some(page_size) => page_size,
+            None => self.get_info(link).await?.page_size(),
+        };
+        let buffer_step = self.payload_size.read.max(1);
+        let flash_step = self.payload_size.flash_read.max(1);
+
+        for page in 0..n_pages {
+            let buffer_reads: Vec<(u16, u16)> = (0..page_size).step_by(buffer_step).map(|address| (buffer_page + page, address)).collect();
+            let flash_reads: Vec<(u16, u16)> = (0..page_size).step_by(flash_step).map(|address| (flash_page + page, address)).collect();
+
+            let buffer: Vec<u8> = self.read_buffer_pipelined(link, &buffer_reads).await?.into_iter().flat_map(|packet| packet.data).collect();
+            let flash: Vec<u8> = self.read_flash_pipelined(link, &flash_reads).await?.into_iter().flat_map(|packet| packet.data).collect();
+            let length = page_size as usize;
+            if buffer.len() < length || flash.len() < length || buffer[..length] != flash[..length] {
+                return Ok(Some(flash_page + page));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Poll the state of the last write command for up to `timeout_duration`
+    //
+    // Fails with a timeout of the whole poll when no idle status or write response is received.
+    // A bootloader busy writing may not answer FLASH_STATUS, and the write response is queued
+    // before the status response. Any response from the target is accepted to see both. An idle
+    // status means that the write is complete if it is known to have been `received`, the command
+    // may also never have been received otherwise. A write in progress is reported unless
+    // waiting for its end.
+    //
+    // The first status received can answer a request of the previous poll, made before the write:
+    // it is not trusted.
+    async fn poll_write<L: Link>(&self, link: &mut L, received: bool, wait: bool, timeout_duration: Duration) -> Result<WriteState> {
+        let packet = Command::FlashStatus.encode(self.target);
+        let start_time = Instant::now();
+        let mut first_status = true;
+
+        while start_time.elapsed() < timeout_duration {
+            let response = match link.request_match_response(&packet, 2).await {
+                Ok(response) => response,
+                // Not answered yet, the write is likely still in progress
+                Err(Error::NoAck { .. } | Error::ResponseTimeout { .. }) => continue,
+                Err(e) => return Err(e),
+            };
+
+            match Response::decode(self.target, &response) {
+                Ok(Response::FlashWrite(response)) => return Ok(WriteState::Done(response)),
+                Ok(Response::FlashStatus(_)) if first_status => first_status = false,
+                Ok(Response::FlashStatus(status)) if status.is_done() || status.error != 0 => {
+                    return Ok(if received { WriteState::Done(status) } else { WriteState::Idle(status) });
+                }
+                // Bootloader answering while writing
+                Ok(Response::FlashStatus(_)) if !wait => return Ok(WriteState::InProgress),
+                // Write in progress, stale response of a previous command or corrupted packet
+                _ => {}
+            }
+        }
+
+        Err(Error::ResponseTimeout { timeout: timeout_duration })
+    }
+
+    pub async fn flash_status<L: Link>(&self, link: &mut L) -> Result<FlashStatusResponse> {
+        match self.request(link, &Command::FlashStatus).await? {
+            Response::FlashStatus(status) => Ok(status),
+            other => Err(other.unexpected("FLASH_STATUS")),
+        }
+    }
+
+    pub async fn read_flash<L: Link>(&self, link: &mut L, page: u16, address: u16) -> Result<FlashReadPacket> {
+        let flash_packet = match self.request(link, &Command::ReadFlash { page, address }).await? {
+            Response::FlashRead(packet) => packet,
+            other => return Err(other.unexpected("READ_FLASH")),
+        };
         
         // Validate response matches request
         if flash_packet.page != page || flash_packet.address != address {
-            return Err(anyhow::anyhow!(
-                "Response mismatch: requested page={}, addr={} but got page={}, addr={} (stale packet detected)", 
-                page, address, flash_packet.page, flash_packet.address
-            ));
+            return Err(Error::ResponseMismatch {
+                expected: Command::ReadFlash { page, address }.encode(self.target),
+                received: Command::ReadFlash { page: flash_packet.page, address: flash_packet.address }.encode(self.target),
+            });
         }
         
         Ok(flash_packet)
     }
 
+    /// Read flash at several `(page, address)` locations, with the requests pipelined on the link
+    ///
+    /// The responses are correlated to the requests by their echoed page and address.
+    pub async fn read_flash_pipelined<L: Link>(&self, link: &mut L, reads: &[(u16, u16)]) -> Result<Vec<FlashReadPacket>> {
+        let commands: Vec<Command> = reads.iter().map(|&(page, address)| Command::ReadFlash { page, address }).collect();
+        self.request_pipelined(link, &commands)
+            .await?
+            .into_iter()
+            .zip(reads)
+            .map(|(response, &(page, address))| match response {
+                Response::FlashRead(packet) if packet.page == page && packet.address == address => Ok(packet),
+                Response::FlashRead(packet) => Err(Error::ResponseMismatch {
+                    expected: Command::ReadFlash { page, address }.encode(self.target),
+                    received: Command::ReadFlash { page: packet.page, address: packet.address }.encode(self.target),
+                }),
+                other => Err(other.unexpected("READ_FLASH")),
+            })
+            .collect()
+    }
+
+    /// Read the RAM buffer at several `(page, address)` locations, with the requests pipelined on the link
+    pub async fn read_buffer_pipelined<L: Link>(&self, link: &mut L, reads: &[(u16, u16)]) -> Result<Vec<BufferReadPacket>> {
+        let commands: Vec<Command> = reads.iter().map(|&(page, address)| Command::ReadBuffer { page, address }).collect();
+        self.request_pipelined(link, &commands)
+            .await?
+            .into_iter()
+            .zip(reads)
+            .map(|(response, &(page, address))| match response {
+                Response::BufferRead(packet) if packet.page == page && packet.address == address => Ok(packet),
+                Response::BufferRead(packet) => Err(Error::ResponseMismatch {
+                    expected: Command::ReadBuffer { page, address }.encode(self.target),
+                    received: Command::ReadBuffer { page: packet.page, address: packet.address }.encode(self.target),
+                }),
+                other => Err(other.unexpected("READ_BUFFER")),
+            })
+            .collect()
+    }
+
+    // Send commands with the requests pipelined and decode their responses, in the same order
+    async fn request_pipelined<L: Link>(&self, link: &mut L, commands: &[Command]) -> Result<Vec<Response>> {
+        let Some(echo_length) = commands.first().map(Command::echo_length) else {
+            return Ok(Vec::new());
+        };
+        let packets: Vec<Vec<u8>> = commands.iter().map(|command| command.encode(self.target)).collect();
+
+        let responses = link.request_pipelined(&packets, echo_length).await?;
+        responses.iter().map(|response| Response::decode(self.target, response)).collect()
+    }
+
     // nRF51822 specific commands (target 0xFE)
-    pub async fn reset_init(&self, bllink: &mut Bllink) -> anyhow::Result<()> {
-        let command = vec![0xff, self.target, CMD_RESET_INIT];
-        bllink.send(&command).await?;
-        Ok(())
+    pub async fn reset_init<L: Link>(&self, link: &mut L) -> Result<()> {
+        self.send(link, &Command::ResetInit).await
     }
 
-    pub async fn reset(&self, bllink: &mut Bllink) -> anyhow::Result<()> {
-        let command = vec![0xff, self.target, CMD_RESET];
-        // No response expected for reset, but use request method
-        let _ = bllink.send(&command).await;
+    pub async fn reset<L: Link>(&self, link: &mut L) -> Result<()> {
+        // No response expected for reset, the bootloader may not acknowledge it
+        let _ = self.send(link, &Command::Reset).await;
         Ok(())
     }
 
-    pub async fn all_off(&self, bllink: &mut Bllink) -> anyhow::Result<()> {
-        let command = vec![0xff, self.target, CMD_ALLOFF];
+    pub async fn all_off<L: Link>(&self, link: &mut L) -> Result<()> {
         // No response expected
-        let _ = bllink.send(&command).await;
+        let _ = self.send(link, &Command::AllOff).await;
         Ok(())
     }
 
-    pub async fn sys_off(&self, bllink: &mut Bllink) -> anyhow::Result<()> {
-        let command = vec![0xff, self.target, CMD_SYSOFF];
+    pub async fn sys_off<L: Link>(&self, link: &mut L) -> Result<()> {
         // No response expected
-        let _ = bllink.send(&command).await;
+        let _ = self.send(link, &Command::SysOff).await;
         Ok(())
     }
 
-    pub async fn sys_on(&self, bllink: &mut Bllink) -> anyhow::Result<()> {
-        let command = vec![0xff, self.target, CMD_SYSON];
+    pub async fn sys_on<L: Link>(&self, link: &mut L) -> Result<()> {
         // No response expected
-        let _ = bllink.send(&command).await;
+        let _ = self.send(link, &Command::SysOn).await;
         Ok(())
     }
 
-    pub async fn get_vbat(&self, bllink: &mut Bllink) -> anyhow::Result<f32> {
-        let command = vec![0xff, self.target, CMD_GETVBAT];
-        let response = bllink.request(&command, SHORT_TIMEOUT).await?;
-        
-        if response.len() < 4 {
-            return Err(anyhow::anyhow!("Invalid VBAT response length"));
+    pub async fn get_vbat<L: Link>(&self, link: &mut L) -> Result<f32> {
+        match self.request(link, &Command::GetVbat).await? {
+            Response::Vbat(vbat) => Ok(vbat),
+            other => Err(other.unexpected("GETVBAT")),
         }
-
-        let vbat_bytes = [response[2], response[3], response[4], response[5]];
-        Ok(f32::from_le_bytes(vbat_bytes))
     }
 }
 
+// Longest wait for a write of `n_pages`, the flash write timeout applies to each page
+fn write_timeout<L: Link>(link: &L, n_pages: u16) -> Duration {
+    link.config().flash_write.timeout.saturating_mul(n_pages.max(1) as u32)
+}
@@ -2,29 +2,53 @@
 // Provide connectivity to both bootloader on the nRF and STM32
 // as well as high-level algorithm to program the Crazyflie 2.x
 
-use crate::Bllink;
-use crate::bootloader::{self, Bootloader};
-use crate::packets::InfoPacket;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-pub struct CFLoader {
-    bllink: Bllink,
+use crate::Bllink;
+use crate::bootloader::{self, Bootloader, PayloadSize};
+use crate::checkpoint::{self, Checkpoint};
+use crate::config::LinkConfig;
+use crate::error::{Error, Result};
+use crate::link::Link;
+use crate::options::{BufferVerify, FlashOptions, PartialPage};
+use crate::packets::{FlashWriteResponse, InfoPacket};
+use crate::plan::{BufferLoad, FlashPlan, PageWrite, PlanStep};
+use crate::verify::{VerifyMode, VerifyReport};
+
+// Number of times corrupted buffer segments are loaded again before giving up
+const BUFFER_RELOAD_ATTEMPTS: usize = 3;
+
+pub struct CFLoader<L: Link = Bllink> {
+    link: L,
     nrf51: Bootloader,
     stm32: Bootloader,
     nrf51_info: InfoPacket,
     stm32_info: InfoPacket,
 }
 
-impl CFLoader {
-    pub async fn new(mut bllink: Bllink) -> anyhow::Result<Self> {
+impl<L: Link> CFLoader<L> {
+    /// Connect to both bootloaders and probe the payload sizes they accept
+    ///
+    /// The probe overwrites the last RAM buffer page of each target. A target whose probe fails
+    /// uses the default payloads, which fit any Crazyradio link.
+    pub async fn new(mut link: L) -> Result<Self> {
         let nrf51 = Bootloader::new(bootloader::TARGET_NRF51);
         let stm32 = Bootloader::new(bootloader::TARGET_STM32);
         
         // Get info from both bootloaders
-        let nrf51_info = nrf51.get_info(&mut bllink).await?;
-        let stm32_info = stm32.get_info(&mut bllink).await?;
+        let nrf51_info = nrf51.get_info(&mut link).await?;
+        let stm32_info = stm32.get_info(&mut link).await?;
+
+        // Largest payloads supported by the link and each bootloader
+        let nrf51 = nrf51.with_payload_size(nrf51.probe_payload_size(&mut link, &nrf51_info).await.unwrap_or_default()).with_info(&nrf51_info);
+        let stm32 = stm32.with_payload_size(stm32.probe_payload_size(&mut link, &stm32_info).await.unwrap_or_default()).with_info(&stm32_info);
         
         Ok(CFLoader { 
-            bllink, 
+            link, 
             nrf51, 
             stm32,
             nrf51_info,
@@ -32,7 +56,13 @@ impl CFLoader {
         })
     }
 
-    pub async fn get_info(&mut self) -> anyhow::Result<String> {
+    /// Connect with the retry and timeout policies of `config` instead of the ones of the link
+    pub async fn with_config(mut link: L, config: LinkConfig) -> Result<Self> {
+        link.set_config(config);
+        CFLoader::new(link).await
+    }
+
+    pub async fn get_info(&mut self) -> Result<String> {
         // Return info from both bootloaders
         Ok(format!(
             "nRF51 Bootloader: {}\nSTM32 Bootloader: {}",
@@ -41,6 +71,11 @@ impl CFLoader {
         ))
     }
 
+    /// Get the link to the bootloaders
+    pub fn link(&self) -> &L {
+        &self.link
+    }
+
     /// Get nRF51 bootloader info
     pub fn nrf51_info(&self) -> &InfoPacket {
         &self.nrf51_info
@@ -51,6 +86,11 @@ impl CFLoader {
         &self.stm32_info
     }
 
+    /// Largest data payloads of the buffer loads and reads of a target, probed when connecting
+    pub fn payload_size(&self, target: u8) -> Result<PayloadSize> {
+        Ok(self.bootloader(target)?.payload_size())
+    }
+
     /// Get a detailed summary of both bootloaders
     pub fn get_bootloader_summary(&self) -> String {
         format!(
@@ -89,11 +129,27 @@ impl CFLoader {
     /// * `start_address` - The starting address in flash where the image should be written
     /// * `image` - The image data to flash
     /// * `progress_callback` - Optional callback function to report progress (bytes_written, total_bytes)
-    pub async fn flash_image_with_progress<F>(&mut self, target: u8, start_address: u32, image: &[u8], mut progress_callback: Option<F>) -> anyhow::Result<()> 
+    pub async fn flash_image_with_progress<F>(&mut self, target: u8, start_address: u32, image: &[u8], mut progress_callback: Option<F>) -> Result<()> 
+    where
+        F: FnMut(usize, usize),
+    {
+        self.flash_image_internal(target, start_address, image, &FlashOptions::default(), 0, &mut progress_callback).await
+    }
+
+    /// Flash an image with non-default options and an optional progress callback
+    ///
+    /// See [FlashOptions] for the available options.
+    pub async fn flash_image_with_options<F>(&mut self, target: u8, start_address: u32, image: &[u8], options: &FlashOptions, mut progress_callback: Option<F>) -> Result<()>
     where
         F: FnMut(usize, usize),
     {
-        self.flash_image_internal(target, start_address, image, &mut progress_callback).await
+        self.check_image(target, start_address, image, options)?;
+        let backup = self.backup_before_flash(target, options).await?;
+
+        match self.flash_image_internal(target, start_address, image, options, 0, &mut progress_callback).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(self.rollback(target, e, backup).await),
+        }
     }
 
     /// Flash an image to either the nRF51 or STM32 bootloader
@@ -102,142 +158,432 @@ impl CFLoader {
     /// * `target` - The bootloader target (use bootloader::TARGET_NRF51 or bootloader::TARGET_STM32)
     /// * `start_address` - The starting address in flash where the image should be written
     /// * `image` - The image data to flash
-    pub async fn flash_image(&mut self, target: u8, start_address: u32, image: &[u8]) -> anyhow::Result<()> {
-        self.flash_image_internal(target, start_address, image, &mut None::<fn(usize, usize)>).await
+    pub async fn flash_image(&mut self, target: u8, start_address: u32, image: &[u8]) -> Result<()> {
+        self.flash_image_internal(target, start_address, image, &FlashOptions::default(), 0, &mut None::<fn(usize, usize)>).await
     }
 
-    /// Internal flash implementation with optional progress callback
-    async fn flash_image_internal<F>(&mut self, target: u8, start_address: u32, image: &[u8], progress_callback: &mut Option<F>) -> anyhow::Result<()> 
+    /// Flash the STM32 and nRF51 images of a platform update in one session
+    ///
+    /// The two flashings are interleaved: the buffer of one target is loaded while the other one
+    /// is writing flash. Progress is reported for both images together. A checkpoint can only be
+    /// made for a single target, with `options.backup` both targets are backed up and restored if
+    /// flashing fails.
+    pub async fn flash_platform<F>(&mut self, stm32_address: u32, stm32_image: &[u8], nrf51_address: u32, nrf51_image: &[u8], options: &FlashOptions, mut progress_callback: Option<F>) -> Result<()>
     where
         F: FnMut(usize, usize),
     {
-        // Get the appropriate bootloader info
-        let (page_size, n_buff_pages, flash_start_page) = match target {
-            bootloader::TARGET_NRF51 => (
-                self.nrf51_info.page_size() as usize,
-                self.nrf51_info.n_buff_page() as usize,
-                self.nrf51_info.flash_start(),
-            ),
-            bootloader::TARGET_STM32 => (
-                self.stm32_info.page_size() as usize,
-                self.stm32_info.n_buff_page() as usize,
-                self.stm32_info.flash_start(),
-            ),
-            _ => return Err(anyhow::anyhow!("Invalid bootloader target: 0x{:02X}", target)),
-        };
-        
-        // Calculate buffer size (total buffer capacity)
-        let buffer_size = page_size * n_buff_pages;
-        
-        // Calculate which flash page corresponds to the start address
-        let start_page = (start_address / page_size as u32) as u16;
-        
-        // Validate that we're writing to a valid flash area
-        if start_page < flash_start_page {
-            return Err(anyhow::anyhow!(
-                "Cannot write to page {} (before flash start page {})", 
-                start_page, flash_start_page
-            ));
+        if options.checkpoint.is_some() {
+            return Err(Error::Checkpoint("a checkpoint is only made when flashing a single target".to_string()));
+        }
+
+        self.check_image(bootloader::TARGET_STM32, stm32_address, stm32_image, options)?;
+        self.check_image(bootloader::TARGET_NRF51, nrf51_address, nrf51_image, options)?;
+        let stm32_backup = self.backup_before_flash(bootloader::TARGET_STM32, options).await?;
+        let nrf51_backup = self.backup_before_flash(bootloader::TARGET_NRF51, options).await?;
+
+        let images = [(bootloader::TARGET_STM32, stm32_address, stm32_image), (bootloader::TARGET_NRF51, nrf51_address, nrf51_image)];
+        match self.flash_platform_internal(images, options, &mut progress_callback).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                let error = self.rollback(bootloader::TARGET_STM32, e, stm32_backup).await;
+                Err(self.rollback(bootloader::TARGET_NRF51, error, nrf51_backup).await)
+            }
+        }
+    }
+
+    async fn flash_platform_internal<F>(&mut self, images: [(u8, u32, &[u8]); 2], options: &FlashOptions, progress_callback: &mut Option<F>) -> Result<()>
+    where
+        F: FnMut(usize, usize),
+    {
+        let mut plans = Vec::with_capacity(images.len());
+        for (target, start_address, image) in images {
+            plans.push(self.prepare_plan(target, start_address, image, options, 0).await?);
         }
 
+        let mut runs: Vec<PlanRun> = plans.iter().map(|(plan, aligned_image)| PlanRun::new(plan, aligned_image)).collect();
+        let mut running = vec![true; runs.len()];
+        let total = plans.iter().map(|(plan, _)| plan.image_length).sum();
+        let mut reported = 0;
 
-        let mut bytes_written = 0;
-        let mut current_address = start_address;
+        // One step of each target in turn, a target writes while the other one is loaded
+        while running.contains(&true) {
+            for (run, running) in runs.iter_mut().zip(&mut running) {
+                if *running {
+                    *running = self.run_step(run, true, &mut None).await?;
+                }
+            }
 
+            let progress = runs.iter().map(PlanRun::progress).sum();
+            if let Some(callback) = progress_callback
+                && progress > reported
+            {
+                reported = progress;
+                callback(reported, total);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Internal flash implementation with optional progress callback
+    ///
+    /// The first `committed` bytes of the page aligned image have already been written by an
+    /// interrupted flashing, they are only written again if they differ from the image.
+    async fn flash_image_internal<F>(&mut self, target: u8, start_address: u32, image: &[u8], options: &FlashOptions, committed: usize, progress_callback: &mut Option<F>) -> Result<()> 
+    where
+        F: FnMut(usize, usize),
+    {
+        let (plan, aligned_image) = self.prepare_plan(target, start_address, image, options, committed).await?;
 
-        while bytes_written < image.len() {
-            
-            // Calculate how much data we can write in this iteration
-            let remaining_bytes = image.len() - bytes_written;
-            let chunk_size = remaining_bytes.min(buffer_size);
-            let chunk = &image[bytes_written..bytes_written + chunk_size];
+        let info = self.info(target)?;
+        let mut checkpoint = options.checkpoint.clone().map(|path| (path, Checkpoint::new(target, start_address, image, info, 0)));
+        self.run_plan(&plan, &aligned_image, &mut checkpoint, progress_callback).await?;
 
-            // Calculate flash pages to write
-            let current_page = (current_address / page_size as u32) as u16;
-            let pages_needed = ((chunk_size + page_size - 1) / page_size) as u16; // Round up
+        if let Some(path) = &options.checkpoint {
+            Checkpoint::remove(path)?;
+        }
 
+        Ok(())
+    }
 
+    /// Plan the flashing of an image without writing anything
+    ///
+    /// The flash is read when the plan depends on its content: around an image that is not page
+    /// aligned with [PartialPage::Preserve], and to find the changed pages with `options.delta`.
+    pub async fn plan_flash(&mut self, target: u8, start_address: u32, image: &[u8], options: &FlashOptions) -> Result<FlashPlan> {
+        Ok(self.prepare_plan(target, start_address, image, options, 0).await?.0)
+    }
 
-            // Load the chunk into the buffer(s)
-            self.load_chunk_to_buffer(target, chunk, page_size).await?;
-            
-            // Flash the buffer to flash memory
-            let result = match target {
-                bootloader::TARGET_NRF51 => {
-                    self.nrf51.write_flash(&mut self.bllink, 0, current_page, pages_needed).await?
-                },
-                bootloader::TARGET_STM32 => {
-                    self.stm32.write_flash(&mut self.bllink, 0, current_page, pages_needed).await?
-                },
-                _ => unreachable!(), // Already validated above
+    /// Execute a plan made by [CFLoader::plan_flash] or [FlashPlan::new] for this image
+    pub async fn execute_plan<F>(&mut self, plan: &FlashPlan, image: &[u8], mut progress_callback: Option<F>) -> Result<()>
+    where
+        F: FnMut(usize, usize),
+    {
+        if image.len() != plan.image_length {
+            return Err(Error::PlanMismatch(format!("image of {} bytes for a plan of {} bytes", image.len(), plan.image_length)));
+        }
+        if checkpoint::image_hash(image) != plan.image_hash {
+            return Err(Error::PlanMismatch("plan made for another image".to_string()));
+        }
+        let info = self.info(plan.target)?;
+        if info.page_size() as usize != plan.page_size {
+            return Err(Error::PlanMismatch(format!("plan made for pages of {} bytes", plan.page_size)));
+        }
+        if plan.n_buff_page > info.n_buff_page() {
+            return Err(Error::PlanMismatch(format!("plan made for {} buffer pages", plan.n_buff_page)));
+        }
+        info.check_bounds(plan.target, plan.address, plan.head + plan.image_length + plan.tail, true)?;
+        if self.payload_size(plan.target)?.load < plan.payload_size.load {
+            return Err(Error::PlanMismatch(format!("plan made for loads of {} bytes", plan.payload_size.load)));
+        }
+
+        let aligned_image = self.pad_image(plan, image).await?;
+        self.run_plan(plan, &aligned_image, &mut None, &mut progress_callback).await
+    }
+
+    // Plan the flashing of an image and complete its partial first and last pages
+    async fn prepare_plan(&mut self, target: u8, start_address: u32, image: &[u8], options: &FlashOptions, committed: usize) -> Result<(FlashPlan, Vec<u8>)> {
+        let info = self.info(target)?;
+        let payload_size = self.payload_size(target)?;
+        let mut plan = FlashPlan::new(target, start_address, image, info, payload_size, options)?;
+        let aligned_image = self.pad_image(&plan, image).await?;
+
+        // Only the parts of the image that differ from the flash are written
+        if options.delta || committed > 0 {
+            let (address, page_size) = (plan.address, plan.page_size);
+            let runs = if options.delta {
+                self.changed_runs(target, address, &aligned_image, page_size).await?
+            } else {
+                let mut runs = self.changed_runs(target, address, &aligned_image[..committed], page_size).await?;
+                match runs.last_mut() {
+                    Some(run) if run.end == committed => run.end = aligned_image.len(),
+                    _ => runs.push(committed..aligned_image.len()),
+                }
+                runs
             };
+            plan = FlashPlan::with_runs(target, start_address, image, self.info(target)?, payload_size, options, &runs)?;
+        }
+
+        if options.dedupe_pages {
+            plan.dedupe_pages(&aligned_image);
+        }
+
+        Ok((plan, aligned_image))
+    }
+
+    /// Execute the steps of a plan on the page aligned image
+    ///
+    /// The checkpoint, if any, is saved after each write of the image. Progress is reported in
+    /// bytes of the original image, the pages skipped by the plan count as written.
+    async fn run_plan<F>(&mut self, plan: &FlashPlan, aligned_image: &[u8], checkpoint: &mut Option<(PathBuf, Checkpoint)>, progress_callback: &mut Option<F>) -> Result<()>
+    where
+        F: FnMut(usize, usize),
+    {
+        let mut run = PlanRun::new(plan, aligned_image);
+        let mut reported = 0;
 
-            // Check if the flash operation was successful
-            if !result.is_success() {
-                return Err(anyhow::anyhow!(
-                    "Flash operation failed at page {}: {}", 
-                    current_page, result.error()
-                ));
+        loop {
+            let running = self.run_step(&mut run, false, checkpoint).await?;
+
+            if let Some(callback) = progress_callback
+                && run.progress() > reported
+            {
+                reported = run.progress();
+                callback(reported, plan.image_length);
             }
 
+            if !running {
+                return Ok(());
+            }
+        }
+    }
 
-            // Update counters
-            bytes_written += chunk_size;
-            current_address += chunk_size as u32;
-            
-            // Call progress callback if provided
-            if let Some(callback) = progress_callback {
-                callback(bytes_written, image.len());
+    /// Execute the next step of a plan, returns false once the plan is complete
+    ///
+    /// The write of a step is only started: it completes while the next pages are loaded, or while
+    /// the other target is used when `interleaved`. An interleaved write is started without
+    /// waiting for the bootloader, which then does not receive anything until the write completes.
+    async fn run_step(&mut self, run: &mut PlanRun<'_>, interleaved: bool, checkpoint: &mut Option<(PathBuf, Checkpoint)>) -> Result<bool> {
+        let target = run.plan.target;
+        let step = run.plan.steps.get(run.next);
+        run.next += 1;
+
+        let erased;
+        let (write, data, image) = match step {
+            Some(PlanStep::Erase(write)) => {
+                erased = vec![0xff; write.range().end];
+                (Some(write), &erased[..], false)
+            }
+            Some(PlanStep::Write(write)) => (Some(write), run.aligned_image, true),
+            Some(PlanStep::Verify(_)) | None => (None, run.aligned_image, false),
+        };
+
+        // Loading buffer pages still being written to flash, or reading flash, waits for the
+        // write in progress. So does any command to a bootloader not answering while writing.
+        let reuses_buffer = |pending: &PendingWrite| {
+            !pending.answering
+                || write.is_none_or(|write| write.loads.iter().any(|load| pending.write.buffer_pages().contains(&load.buffer_page)))
+        };
+        if let Some(pending) = run.in_progress.take_if(|pending| reuses_buffer(pending)) {
+            self.finish_write(run, pending, checkpoint).await?;
+        }
+
+        if let Some(PlanStep::Verify(range)) = step {
+            let address = run.plan.address + range.start as u32;
+            let report = self.verify(target, address, &run.aligned_image[range.clone()]).await?;
+            if !report.is_ok() {
+                return Err(Error::VerifyFailed { target, pages: report.mismatched_pages() });
             }
         }
+        let Some(write) = write else {
+            return Ok(step.is_some());
+        };
 
+        self.load_buffer_pages(target, data, &write.loads, run.plan.buffer_verify).await?;
+        if let Some(pending) = run.in_progress.take() {
+            self.finish_write(run, pending, checkpoint).await?;
+        }
+
+        let bootloader = self.bootloader(target)?;
+        let (buffer_page, flash_page, n_pages) = (write.buffer_page, write.flash_page, write.n_pages);
+        let started = if interleaved {
+            match bootloader.send_write_flash(&mut self.link, buffer_page, flash_page, n_pages).await? {
+                true => Some(PendingWrite { write, image, answering: false }),
+                false => {
+                    let result = bootloader.confirm_write_flash(&mut self.link, buffer_page, flash_page, n_pages).await?;
+                    check_write(target, write, result)?;
+                    None
+                }
+            }
+        } else {
+            match bootloader.start_write_flash(&mut self.link, buffer_page, flash_page, n_pages).await? {
+                Some(result) => {
+                    check_write(target, write, result)?;
+                    None
+                }
+                None => Some(PendingWrite { write, image, answering: true }),
+            }
+        };
+
+        match started {
+            Some(pending) => run.in_progress = Some(pending),
+            None if image => run.completed(write, checkpoint)?,
+            None => {}
+        }
+
+        Ok(true)
+    }
+
+    /// Wait for the end of a write started by `run_step`
+    async fn finish_write(&mut self, run: &mut PlanRun<'_>, pending: PendingWrite<'_>, checkpoint: &mut Option<(PathBuf, Checkpoint)>) -> Result<()> {
+        let target = run.plan.target;
+        let bootloader = self.bootloader(target)?;
+        let result = bootloader.wait_write_flash(&mut self.link, pending.write.n_pages).await?;
+        check_write(target, pending.write, result)?;
+
+        if pending.image {
+            run.completed(pending.write, checkpoint)?;
+        }
         Ok(())
     }
 
-    /// Load a chunk of data into the bootloader's buffer pages
-    async fn load_chunk_to_buffer(&mut self, target: u8, chunk: &[u8], page_size: usize) -> anyhow::Result<()> {
-        let mut chunk_offset = 0;
-        let mut buffer_page = 0u16;
-
-        while chunk_offset < chunk.len() {
-            let remaining_in_chunk = chunk.len() - chunk_offset;
-            let bytes_to_write = remaining_in_chunk.min(page_size);
-            
-            // Load data into the current buffer page
-            let mut page_offset = 0u16;
-            let mut bytes_written_to_page = 0;
-
-            while bytes_written_to_page < bytes_to_write {
-                // Calculate how much we can write in this load_buffer call (max 25 bytes per call)
-                let remaining_in_page = bytes_to_write - bytes_written_to_page;
-                let load_size = remaining_in_page.min(25); // reduced from 27 to 25 due to missing last 2 bytes
-                
-                let data_slice = &chunk[chunk_offset + bytes_written_to_page..chunk_offset + bytes_written_to_page + load_size];
-                let _global_offset = chunk_offset + bytes_written_to_page;
-                
-                match target {
-                    bootloader::TARGET_NRF51 => {
-                        self.nrf51.load_buffer(&mut self.bllink, buffer_page, page_offset, data_slice).await?;
-                    },
-                    bootloader::TARGET_STM32 => {
-                        self.stm32.load_buffer(&mut self.bllink, buffer_page, page_offset, data_slice).await?;
-                    },
-                    _ => return Err(anyhow::anyhow!("Invalid bootloader target: 0x{:02X}", target)),
+    /// Complete the partial first and last pages of an image, as planned
+    async fn pad_image(&mut self, plan: &FlashPlan, image: &[u8]) -> Result<Vec<u8>> {
+        let (head, tail) = (plan.head, plan.tail);
+        let length = head + image.len() + tail;
+        let mut padded = Vec::with_capacity(length);
+
+        match plan.partial_pages {
+            PartialPage::Preserve => {
+                padded.extend(self.read_flash(plan.target, plan.address, head as u32).await?);
+                padded.extend_from_slice(image);
+                padded.extend(self.read_flash(plan.target, plan.address + (head + image.len()) as u32, tail as u32).await?);
+            }
+            PartialPage::Erase => {
+                padded.resize(head, 0xff);
+                padded.extend_from_slice(image);
+                padded.resize(length, 0xff);
+            }
+        }
+
+        Ok(padded)
+    }
+
+    /// Resume a flashing interrupted by a radio or process failure
+    ///
+    /// The checkpoint file of `options.checkpoint` is checked against the image and the device,
+    /// see [Checkpoint::validate]. The pages it reports as written are read back and compared with the image, the
+    /// ones that differ (e.g. on another device) are written again and the flashing continues
+    /// after them. Without a checkpoint file the image is flashed from the start.
+    pub async fn resume_flash<F>(&mut self, target: u8, start_address: u32, image: &[u8], options: &FlashOptions, mut progress_callback: Option<F>) -> Result<()>
+    where
+        F: FnMut(usize, usize),
+    {
+        let Some(path) = &options.checkpoint else {
+            return Err(Error::Checkpoint("No checkpoint file in the flash options".to_string()));
+        };
+
+        let committed = match Checkpoint::load(path)? {
+            Some(checkpoint) => {
+                checkpoint.validate(target, start_address, image, self.info(target)?)?;
+                checkpoint.committed_length()
+            }
+            None => 0,
+        };
+
+        self.flash_image_internal(target, start_address, image, options, committed, &mut progress_callback).await
+    }
+
+    /// Compare the flash to the image page by page, returns the runs of consecutive pages that changed
+    ///
+    /// The runs are returned as offsets in the image. Each page is read with pipelined requests.
+    async fn changed_runs(&mut self, target: u8, start_address: u32, image: &[u8], page_size: usize) -> Result<Vec<Range<usize>>> {
+        let mut runs: Vec<Range<usize>> = Vec::new();
+        let mut begin = 0;
+
+        while begin < image.len() {
+            // Part of the image in the page containing `begin`
+            let address = start_address + begin as u32;
+            let end = (begin + page_size - address as usize % page_size).min(image.len());
+
+            let flash = self.read_flash(target, address, (end - begin) as u32).await?;
+            let changed = flash[..] != image[begin..end];
+
+            if changed {
+                match runs.last_mut() {
+                    Some(run) if run.end == begin => run.end = end,
+                    _ => runs.push(begin..end),
                 }
-                
-                page_offset += load_size as u16;
-                bytes_written_to_page += load_size;
             }
+            begin = end;
+        }
+
+        Ok(runs)
+    }
+
+    /// Load data into the bootloader's buffer pages, `loads` are ranges of `data`
+    ///
+    /// With buffer verification enabled the loaded segments are read back and the corrupted ones
+    /// are loaded again, so that a bad load is never written to flash.
+    async fn load_buffer_pages(&mut self, target: u8, data: &[u8], loads: &[BufferLoad], verify: BufferVerify) -> Result<()> {
+        let bootloader = self.bootloader(target)?;
+
+        self.load_segments(bootloader, data, loads.iter()).await?;
+
+        let all: Vec<&BufferLoad> = loads.iter().collect();
+        let mut corrupted = match verify {
+            BufferVerify::Off => return Ok(()),
+            BufferVerify::Full => self.corrupted_segments(bootloader, data, &all).await?,
+            BufferVerify::Sampled(n) => {
+                let sample: Vec<&BufferLoad> = loads.iter().step_by(n.max(1)).collect();
+                let corrupted = self.corrupted_segments(bootloader, data, &sample).await?;
+                if corrupted.is_empty() {
+                    corrupted
+                } else {
+                    // Loads are being corrupted, the other segments cannot be trusted either
+                    self.corrupted_segments(bootloader, data, &all).await?
+                }
+            }
+        };
+
+        for _ in 0..BUFFER_RELOAD_ATTEMPTS {
+            if corrupted.is_empty() {
+                return Ok(());
+            }
+            self.load_segments(bootloader, data, corrupted.iter().copied()).await?;
+            corrupted = self.corrupted_segments(bootloader, data, &corrupted).await?;
+        }
 
-            chunk_offset += bytes_to_write;
-            buffer_page += 1;
+        match corrupted.first() {
+            Some(load) => Err(Error::BufferMismatch { target, page: load.buffer_page, address: load.address }),
+            None => Ok(()),
         }
+    }
 
-        Ok(())
+    // Send the LOAD_BUFFER packets of buffer segments
+    async fn load_segments<'a>(&mut self, bootloader: Bootloader, data: &[u8], loads: impl Iterator<Item = &'a BufferLoad>) -> Result<()> {
+        let segments: Vec<(u16, u16, &[u8])> = loads.map(|load| (load.buffer_page, load.address, &data[load.range.clone()])).collect();
+        bootloader.load_buffer_pipelined(&mut self.link, &segments).await
+    }
+
+    // Read back buffer segments, returns the ones that do not contain the loaded data
+    async fn corrupted_segments<'a>(&mut self, bootloader: Bootloader, data: &[u8], loads: &[&'a BufferLoad]) -> Result<Vec<&'a BufferLoad>> {
+        let reads: Vec<(u16, u16)> = loads.iter().map(|load| (load.buffer_page, load.address)).collect();
+        let packets = bootloader.read_buffer_pipelined(&mut self.link, &reads).await?;
+
+        Ok(loads
+            .iter()
+            .zip(&packets)
+            .filter(|(load, read)| !read.data.starts_with(&data[load.range.clone()]))
+            .map(|(&load, _)| load)
+            .collect())
+    }
+
+    // Bootloader of a target
+    fn bootloader(&self, target: u8) -> Result<Bootloader> {
+        match target {
+            bootloader::TARGET_NRF51 => Ok(self.nrf51),
+            bootloader::TARGET_STM32 => Ok(self.stm32),
+            _ => Err(Error::InvalidTarget(target)),
+        }
+    }
+
+    // Check that `length` bytes at `address` are in the flash of a target
+    fn check_bounds(&self, target: u8, address: u32, length: usize, write: bool) -> Result<()> {
+        self.info(target)?.check_bounds(target, address, length, write)
+    }
+
+    // Bootloader info of a target
+    fn info(&self, target: u8) -> Result<&InfoPacket> {
+        match target {
+            bootloader::TARGET_NRF51 => Ok(&self.nrf51_info),
+            bootloader::TARGET_STM32 => Ok(&self.stm32_info),
+            _ => Err(Error::InvalidTarget(target)),
+        }
     }
 
     /// Flash an image to the STM32 bootloader with progress callback
-    pub async fn flash_stm32_with_progress<F>(&mut self, start_address: u32, image: &[u8], progress_callback: Option<F>) -> anyhow::Result<()> 
+    pub async fn flash_stm32_with_progress<F>(&mut self, start_address: u32, image: &[u8], progress_callback: Option<F>) -> Result<()> 
     where
         F: FnMut(usize, usize),
     {
@@ -245,7 +591,7 @@ impl CFLoader {
     }
 
     /// Flash an image to the nRF51 bootloader with progress callback
-    pub async fn flash_nrf51_with_progress<F>(&mut self, start_address: u32, image: &[u8], progress_callback: Option<F>) -> anyhow::Result<()> 
+    pub async fn flash_nrf51_with_progress<F>(&mut self, start_address: u32, image: &[u8], progress_callback: Option<F>) -> Result<()> 
     where
         F: FnMut(usize, usize),
     {
@@ -253,12 +599,12 @@ impl CFLoader {
     }
 
     /// Flash an image to the STM32 bootloader
-    pub async fn flash_stm32(&mut self, start_address: u32, image: &[u8]) -> anyhow::Result<()> {
+    pub async fn flash_stm32(&mut self, start_address: u32, image: &[u8]) -> Result<()> {
         self.flash_image(bootloader::TARGET_STM32, start_address, image).await
     }
 
     /// Flash an image to the nRF51 bootloader
-    pub async fn flash_nrf51(&mut self, start_address: u32, image: &[u8]) -> anyhow::Result<()> {
+    pub async fn flash_nrf51(&mut self, start_address: u32, image: &[u8]) -> Result<()> {
         self.flash_image(bootloader::TARGET_NRF51, start_address, image).await
     }
 
@@ -270,68 +616,303 @@ impl CFLoader {
     /// * `length` - The number of bytes to read
     /// 
     /// # Returns
-    /// A Vec<u8> containing the read flash content
-    pub async fn read_flash(&mut self, target: u8, start_address: u32, length: u32) -> anyhow::Result<Vec<u8>> {
-        // Get the appropriate bootloader info
-        let page_size = match target {
-            bootloader::TARGET_NRF51 => self.nrf51_info.page_size() as usize,
-            bootloader::TARGET_STM32 => self.stm32_info.page_size() as usize,
-            _ => return Err(anyhow::anyhow!("Invalid bootloader target: 0x{:02X}", target)),
-        };
-
+    /// A Vec<u8> containing the read flash content, an error if the bootloader returns less
+    pub async fn read_flash(&mut self, target: u8, start_address: u32, length: u32) -> Result<Vec<u8>> {
+        self.check_bounds(target, start_address, length as usize, false)?;
+        let bootloader = self.bootloader(target)?;
+        let page_size = self.info(target)?.page_size() as u32;
+        let mut read_size = bootloader.payload_size().flash_read;
 
         let mut result = Vec::with_capacity(length as usize);
-        let mut bytes_read = 0u32;
-        let mut current_address = start_address;
-
-        // The bootloader can read up to 27 bytes per read_flash call (based on protocol limit)
-        const MAX_READ_SIZE: usize = 27;
-
-        while bytes_read < length {
-            let remaining_bytes = length - bytes_read;
-            let read_size = (remaining_bytes as usize).min(MAX_READ_SIZE);
-
-            // Calculate page and offset within page
-            let current_page = (current_address / page_size as u32) as u16;
-            let page_offset = (current_address % page_size as u32) as u16;
-
-            // Read from flash
-            let flash_data = match target {
-                bootloader::TARGET_NRF51 => {
-                    self.nrf51.read_flash(&mut self.bllink, current_page, page_offset).await?
-                },
-                bootloader::TARGET_STM32 => {
-                    self.stm32.read_flash(&mut self.bllink, current_page, page_offset).await?
-                },
-                _ => unreachable!(), // Already validated above
-            };
 
-            // Take only the bytes we need (the response might contain more data than requested)
-            let data_to_take = read_size.min(flash_data.data.len());
-            
-            if data_to_take == 0 {
-                break;
+        // The reads are pipelined one page at a time, assuming that each response carries a full
+        // packet of data. A shorter response ends the batch, the reads continue after the bytes
+        // received with the size of that response.
+        while (result.len() as u32) < length {
+            let address = start_address + result.len() as u32;
+            let batch_end = ((address / page_size + 1) * page_size).min(start_address + length);
+            let reads: Vec<(u16, u16)> = (address..batch_end)
+                .step_by(read_size)
+                .map(|address| ((address / page_size) as u16, (address % page_size) as u16))
+                .collect();
+
+            let packets = bootloader.read_flash_pipelined(&mut self.link, &reads).await?;
+            let batch_start = result.len();
+            for packet in &packets {
+                // Take the bytes up to the address of the next read, a response can carry more
+                let wanted = read_size.min((batch_end - start_address) as usize - result.len());
+                result.extend_from_slice(&packet.data[..wanted.min(packet.data.len())]);
+                if packet.data.len() < wanted {
+                    read_size = packet.data.len().max(1);
+                    break;
+                }
             }
-            
-            result.extend_from_slice(&flash_data.data[..data_to_take]);
 
-            bytes_read += data_to_take as u32;
-            current_address += data_to_take as u32;
+            // The flash is in bounds, a read answered without data would never complete
+            if result.len() == batch_start {
+                return Err(Error::ShortRead { target, address, missing: length as usize - result.len() });
+            }
         }
 
         Ok(result)
     }
 
     /// Read flash content from the STM32 bootloader
-    pub async fn read_stm32_flash(&mut self, start_address: u32, length: u32) -> anyhow::Result<Vec<u8>> {
+    pub async fn read_stm32_flash(&mut self, start_address: u32, length: u32) -> Result<Vec<u8>> {
         self.read_flash(bootloader::TARGET_STM32, start_address, length).await
     }
 
     /// Read flash content from the nRF51 bootloader  
-    pub async fn read_nrf51_flash(&mut self, start_address: u32, length: u32) -> anyhow::Result<Vec<u8>> {
+    pub async fn read_nrf51_flash(&mut self, start_address: u32, length: u32) -> Result<Vec<u8>> {
         self.read_flash(bootloader::TARGET_NRF51, start_address, length).await
     }
 
+    /// Read back the flash and compare it to an image
+    ///
+    /// All the differences are collected in the returned report, an error is only returned when
+    /// the flash cannot be read.
+    pub async fn verify(&mut self, target: u8, start_address: u32, image: &[u8]) -> Result<VerifyReport> {
+        self.verify_with_mode(target, start_address, image, VerifyMode::Full).await
+    }
+
+    /// Compare the flash to an image, reading back all of it or only a sample
+    pub async fn verify_with_mode(&mut self, target: u8, start_address: u32, image: &[u8], mode: VerifyMode) -> Result<VerifyReport> {
+        self.check_bounds(target, start_address, image.len(), false)?;
+        let bootloader = self.bootloader(target)?;
+        let page_size = self.info(target)?.page_size() as u32;
+        let read_size = bootloader.payload_size().flash_read;
+        let every = match mode {
+            VerifyMode::Full => 1,
+            VerifyMode::Sampled(n) => n.max(1),
+        };
+
+        let start_time = Instant::now();
+        let mut report = VerifyReport::new(target, start_address, image.len());
+
+        // Reads never cross a page boundary so that each mismatch belongs to one page
+        let mut reads = Vec::new();
+        let mut offset = 0;
+        while offset < image.len() {
+            let address = start_address + offset as u32;
+            let page_offset = address % page_size;
+            let len = read_size.min((page_size - page_offset) as usize).min(image.len() - offset);
+            reads.push((offset, len));
+            offset += len;
+        }
+        let reads: Vec<(usize, usize)> = reads.into_iter().step_by(every).collect();
+
+        let locations: Vec<(u16, u16)> = reads
+            .iter()
+            .map(|&(offset, _)| {
+                let address = start_address + offset as u32;
+                ((address / page_size) as u16, (address % page_size) as u16)
+            })
+            .collect();
+        let packets = bootloader.read_flash_pipelined(&mut self.link, &locations).await?;
+
+        for (&(offset, len), packet) in reads.iter().zip(&packets) {
+            let address = start_address + offset as u32;
+            report.compare(packet.page, packet.address, address, &image[offset..offset + len], &packet.data);
+        }
+
+        report.duration = start_time.elapsed();
+        Ok(report)
+    }
+
+    /// Flash an image, verify it and flash again only the pages that do not match
+    ///
+    /// The pages failing verification are flashed and verified again up to `options.repair_attempts`
+    /// times. The returned report lists the repaired pages and the mismatches remaining after the
+    /// last attempt: an error is only returned when communication with the bootloader fails.
+    ///
+    /// With `options.backup` set, a remaining mismatch is an error as the backup is restored.
+    pub async fn flash_and_verify<F>(&mut self, target: u8, start_address: u32, image: &[u8], options: &FlashOptions, mut progress_callback: Option<F>) -> Result<VerifyReport>
+    where
+        F: FnMut(usize, usize),
+    {
+        self.check_image(target, start_address, image, options)?;
+        let backup = self.backup_before_flash(target, options).await?;
+
+        let error = match self.flash_and_verify_internal(target, start_address, image, options, &mut progress_callback).await {
+            Ok(report) if report.is_ok() || backup.is_none() => return Ok(report),
+            Ok(report) => Error::VerifyFailed { target, pages: report.mismatched_pages() },
+            Err(e) => e,
+        };
+        Err(self.rollback(target, error, backup).await)
+    }
+
+    async fn flash_and_verify_internal<F>(&mut self, target: u8, start_address: u32, image: &[u8], options: &FlashOptions, progress_callback: &mut Option<F>) -> Result<VerifyReport>
+    where
+        F: FnMut(usize, usize),
+    {
+        self.flash_image_internal(target, start_address, image, options, 0, progress_callback).await?;
 
+        let page_size = self.info(target)?.page_size() as u32;
+        let mut report = self.verify(target, start_address, image).await?;
 
-}
\ No newline at end of file
+        // The pages to repair are known to differ and are not part of the checkpointed image
+        let repair_options = FlashOptions { delta: false, checkpoint: None, backup: None, ..options.clone() };
+
+        for _ in 0..options.repair_attempts {
+            let pages = report.mismatched_pages();
+            if pages.is_empty() {
+                break;
+            }
+
+            let mut mismatches = Vec::new();
+            for page in pages {
+                // Part of the image in this page
+                let begin = ((page as u32 * page_size).max(start_address) - start_address) as usize;
+                let end = (((page as u32 + 1) * page_size - start_address) as usize).min(image.len());
+                let address = start_address + begin as u32;
+
+                self.flash_image_internal(target, address, &image[begin..end], &repair_options, 0, &mut None::<fn(usize, usize)>).await?;
+                let page_report = self.verify(target, address, &image[begin..end]).await?;
+
+                report.duration += page_report.duration;
+                if !report.repaired_pages.contains(&page) {
+                    report.repaired_pages.push(page);
+                }
+                mismatches.extend(page_report.mismatches);
+            }
+            report.mismatches = mismatches;
+        }
+
+        Ok(report)
+    }
+
+    /// Save the firmware region of a target, from `flash_start` to the end of the flash
+    ///
+    /// The region is written to a new file in `dir` named after the target and the current time,
+    /// for example `stm32-1735689600.bin`. An existing backup is never overwritten, a backup made
+    /// in the same second gets a numbered suffix such as `stm32-1735689600-1.bin`. Returns the
+    /// path of the file.
+    pub async fn backup(&mut self, target: u8, dir: impl AsRef<Path>) -> Result<PathBuf> {
+        let info = self.info(target)?;
+        let page_size = info.page_size() as u32;
+        let start_address = info.flash_start() as u32 * page_size;
+        let length = (info.n_flash_page() as u32).saturating_sub(info.flash_start() as u32) * page_size;
+
+        let firmware = self.read_flash(target, start_address, length).await?;
+
+        let name = match target {
+            bootloader::TARGET_NRF51 => "nrf51",
+            _ => "stm32",
+        };
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+        fs::create_dir_all(&dir)?;
+        for suffix in 0.. {
+            let path = match suffix {
+                0 => dir.as_ref().join(format!("{}-{}.bin", name, timestamp)),
+                _ => dir.as_ref().join(format!("{}-{}-{}.bin", name, timestamp, suffix)),
+            };
+            match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(&firmware)?;
+                    return Ok(path);
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        unreachable!()
+    }
+
+    /// Flash back a firmware region saved by [CFLoader::backup]
+    ///
+    /// Only the pages that differ from the backup are written.
+    pub async fn restore(&mut self, target: u8, backup: impl AsRef<Path>) -> Result<()> {
+        let firmware = fs::read(backup)?;
+        let info = self.info(target)?;
+        let start_address = info.flash_start() as u32 * info.page_size() as u32;
+
+        let options = FlashOptions { delta: true, ..FlashOptions::default() };
+        self.flash_image_internal(target, start_address, &firmware, &options, 0, &mut None::<fn(usize, usize)>).await
+    }
+
+    // Check that an image can be flashed, before the backup reads the device
+    fn check_image(&self, target: u8, start_address: u32, image: &[u8], options: &FlashOptions) -> Result<()> {
+        FlashPlan::new(target, start_address, image, self.info(target)?, self.payload_size(target)?, options).map(|_| ())
+    }
+
+    // Backup of the firmware region before flashing, if enabled in the options
+    async fn backup_before_flash(&mut self, target: u8, options: &FlashOptions) -> Result<Option<PathBuf>> {
+        match &options.backup {
+            Some(dir) => Ok(Some(self.backup(target, dir).await?)),
+            None => Ok(None),
+        }
+    }
+
+    // Restore the backup after a failed flashing, returns the error to report
+    async fn rollback(&mut self, target: u8, error: Error, backup: Option<PathBuf>) -> Error {
+        let Some(backup) = backup else {
+            return error;
+        };
+
+        match self.restore(target, &backup).await {
+            Ok(()) => Error::RolledBack { error: Box::new(error), backup },
+            // The backup file is kept for a manual restore
+            Err(rollback) => Error::RollbackFailed { error: Box::new(error), rollback: Box::new(rollback), backup },
+        }
+    }
+}
+
+// Execution of a plan, advanced one step at a time by `CFLoader::run_step` so that the plans of
+// both targets can be interleaved
+struct PlanRun<'a> {
+    plan: &'a FlashPlan,
+    aligned_image: &'a [u8],
+    // Index of the next step
+    next: usize,
+    in_progress: Option<PendingWrite<'a>>,
+    // Bytes of the aligned image written
+    written: usize,
+}
+
+// Flash write started and not known to be complete
+struct PendingWrite<'a> {
+    write: &'a PageWrite,
+    // True if it writes pages of the image
+    image: bool,
+    // True if the bootloader answers while writing, other buffer pages can then be loaded
+    answering: bool,
+}
+
+impl<'a> PlanRun<'a> {
+    fn new(plan: &'a FlashPlan, aligned_image: &'a [u8]) -> Self {
+        PlanRun { plan, aligned_image, next: 0, in_progress: None, written: 0 }
+    }
+
+    // Bytes of the original image written, the pages skipped by the plan count as written
+    fn progress(&self) -> usize {
+        if self.next > self.plan.steps.len() {
+            self.plan.image_length
+        } else {
+            self.written.saturating_sub(self.plan.head).min(self.plan.image_length)
+        }
+    }
+
+    // Record a completed write of image pages in the checkpoint
+    //
+    // The checkpoint only moves forward: with safe ordering the first page is written last, the
+    // pages before the last one written are all read back when resuming.
+    fn completed(&mut self, write: &PageWrite, checkpoint: &mut Option<(PathBuf, Checkpoint)>) -> Result<()> {
+        if let Some((path, checkpoint)) = checkpoint {
+            checkpoint.last_page = checkpoint.last_page.max(write.flash_page + write.n_pages - 1);
+            checkpoint.save(path)?;
+        }
+
+        let end = (write.flash_page + write.n_pages) as usize * self.plan.page_size - self.plan.address as usize;
+        self.written = self.written.max(end);
+        Ok(())
+    }
+}
+
+// Check if the flash operation was successful
+fn check_write(target: u8, write: &PageWrite, result: FlashWriteResponse) -> Result<()> {
+    if !result.is_success() {
+        return Err(Error::Flash { target, page: write.flash_page, error: result.error() });
+    }
+    Ok(())
+}
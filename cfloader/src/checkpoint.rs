@@ -0,0 +1,209 @@
+// Flashing checkpoint
+// The checkpoint file is updated after each successful flash write so that an interrupted
+// flashing (radio unplugged, process killed, ...) can be resumed instead of restarted.
+//
+// Checkpoint format, a header line then one "<key> <value>" per line, numbers in hexadecimal:
+//   # cfloader checkpoint v1
+//   target ff
+//   address 00004000
+//   length 0003e2a8
+//   hash 5f3a...          FNV-1a 64 hash of the image
+//   page_size 0400
+//   n_flash_page 0400
+//   flash_start 0010
+//   cpu_id 0123...        Only when the bootloader reports one
+//   last_page 0023        Last flash page written successfully
+//
+// The device is identified by its target, flash geometry and CPU id. The CPU id is a legacy
+// field that most bootloaders leave blank, so a checkpoint does not prove which device it has
+// been made on: the pages it reports as written are also read back and compared with the image
+// before being skipped.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::packets::InfoPacket;
+
+const CHECKPOINT_HEADER: &str = "# cfloader checkpoint v1";
+
+/// FNV-1a 64 bits hash, used to recognize the image a checkpoint has been made for
+pub fn image_hash(image: &[u8]) -> u64 {
+    image.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3))
+}
+
+/// Progress of an image flashing, saved after each flash write
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub target: u8,
+    pub start_address: u32,
+    pub image_length: usize,
+    pub image_hash: u64,
+    pub page_size: u16,
+    pub n_flash_page: u16,
+    pub flash_start: u16,
+    /// CPU id of the device, None when the bootloader leaves it blank
+    pub cpu_id: Option<[u8; 12]>,
+    /// Last flash page written successfully
+    pub last_page: u16,
+}
+
+impl Checkpoint {
+    /// Checkpoint of `image` flashed on a device, up to and including `last_page`
+    pub fn new(target: u8, start_address: u32, image: &[u8], info: &InfoPacket, last_page: u16) -> Self {
+        Checkpoint {
+            target,
+            start_address,
+            image_length: image.len(),
+            image_hash: image_hash(image),
+            page_size: info.page_size(),
+            n_flash_page: info.n_flash_page(),
+            flash_start: info.flash_start(),
+            cpu_id: device_cpu_id(info),
+            last_page,
+        }
+    }
+
+    /// Check that the checkpoint has been made for this image and this device
+    ///
+    /// The device is only recognized by its CPU id when both it and the checkpoint have one,
+    /// the written pages must be read back before being trusted.
+    pub fn validate(&self, target: u8, start_address: u32, image: &[u8], info: &InfoPacket) -> Result<()> {
+        if self.target != target || self.start_address != start_address {
+            return Err(Error::Checkpoint(format!(
+                "Checkpoint made for target 0x{:02X} at 0x{:08X}, not target 0x{:02X} at 0x{:08X}",
+                self.target, self.start_address, target, start_address
+            )));
+        }
+        if self.image_length != image.len() || self.image_hash != image_hash(image) {
+            return Err(Error::Checkpoint("Checkpoint made for another image".to_string()));
+        }
+        if self.page_size != info.page_size() || self.n_flash_page != info.n_flash_page() || self.flash_start != info.flash_start() {
+            return Err(Error::Checkpoint("Checkpoint made for another flash geometry".to_string()));
+        }
+        if let (Some(cpu_id), Some(device)) = (self.cpu_id, device_cpu_id(info))
+            && cpu_id != device
+        {
+            return Err(Error::Checkpoint(format!("Checkpoint made for CPU {:02x?}, not {:02x?}", cpu_id, device)));
+        }
+        Ok(())
+    }
+
+    /// Number of bytes at the start of the page aligned image that have been written
+    ///
+    /// The page aligned image starts at the flash page holding the start address and ends with
+    /// the page holding the end of the image, the length is a whole number of pages.
+    pub fn committed_length(&self) -> usize {
+        let page_size = self.page_size as usize;
+        let aligned_start = self.start_address as usize / page_size * page_size;
+        let aligned_length = (self.start_address as usize - aligned_start + self.image_length).div_ceil(page_size) * page_size;
+        let end = (self.last_page as usize + 1) * page_size;
+        end.saturating_sub(aligned_start).min(aligned_length) / page_size * page_size
+    }
+
+    /// Read a checkpoint file, returns None if it does not exist
+    pub fn load(path: impl AsRef<Path>) -> Result<Option<Self>> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        if text.lines().next() != Some(CHECKPOINT_HEADER) {
+            return Err(Error::Checkpoint("Not a cfloader checkpoint file".to_string()));
+        }
+
+        let cpu_id = match find_field(&text, "cpu_id") {
+            Some(value) => {
+                let bytes = (0..value.len())
+                    .step_by(2)
+                    .map(|i| value.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+                    .collect::<Option<Vec<u8>>>();
+                match bytes.and_then(|bytes| <[u8; 12]>::try_from(bytes).ok()) {
+                    Some(cpu_id) => Some(cpu_id),
+                    None => return Err(Error::Checkpoint(format!("Invalid 'cpu_id' in checkpoint: {}", value))),
+                }
+            }
+            None => None,
+        };
+
+        Ok(Some(Checkpoint {
+            target: number(&text, "target")?,
+            start_address: number(&text, "address")?,
+            image_length: number(&text, "length")?,
+            image_hash: number(&text, "hash")?,
+            page_size: number(&text, "page_size")?,
+            n_flash_page: number(&text, "n_flash_page")?,
+            flash_start: number(&text, "flash_start")?,
+            cpu_id,
+            last_page: number(&text, "last_page")?,
+        }))
+    }
+
+    /// Write the checkpoint file
+    ///
+    /// The file is replaced atomically so that a process killed while saving never leaves a
+    /// truncated checkpoint.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let cpu_id = match self.cpu_id {
+            Some(cpu_id) => format!("cpu_id {}\n", cpu_id.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()),
+            None => String::new(),
+        };
+        let text = format!(
+            "{}\ntarget {:02x}\naddress {:08x}\nlength {:08x}\nhash {:016x}\npage_size {:04x}\nn_flash_page {:04x}\nflash_start {:04x}\n{}last_page {:04x}\n",
+            CHECKPOINT_HEADER,
+            self.target,
+            self.start_address,
+            self.image_length,
+            self.image_hash,
+            self.page_size,
+            self.n_flash_page,
+            self.flash_start,
+            cpu_id,
+            self.last_page
+        );
+
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        fs::write(&temporary, text)?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    /// Delete a checkpoint file, once the flashing is complete
+    pub fn remove(path: impl AsRef<Path>) -> Result<()> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+// CPU id reported by a bootloader, None when it is left blank
+fn device_cpu_id(info: &InfoPacket) -> Option<[u8; 12]> {
+    let cpu_id = *info.cpu_id();
+    if cpu_id.iter().all(|&byte| byte == 0x00) || cpu_id.iter().all(|&byte| byte == 0xff) {
+        None
+    } else {
+        Some(cpu_id)
+    }
+}
+
+// Value of a "<key> <value>" line of a checkpoint file
+fn find_field<'a>(text: &'a str, key: &str) -> Option<&'a str> {
+    text.lines()
+        .filter_map(|line| line.split_once(' '))
+        .find(|(k, _)| *k == key)
+        .map(|(_, value)| value.trim())
+}
+
+// Hexadecimal number of a checkpoint file, rejected if it does not fit its field
+fn number<T: TryFrom<u64>>(text: &str, key: &str) -> Result<T> {
+    let value = find_field(text, key).ok_or_else(|| Error::Checkpoint(format!("Missing '{}' in checkpoint", key)))?;
+    u64::from_str_radix(value, 16)
+        .ok()
+        .and_then(|number| T::try_from(number).ok())
+        .ok_or_else(|| Error::Checkpoint(format!("Invalid '{}' in checkpoint: {}", key, value)))
+}
@@ -0,0 +1,271 @@
+// Bootloader packet codec
+// Every bootloader packet starts with [0xff, target, command]. Commands are encoded from
+// the Command enum and responses decoded to the Response enum. Decoding never panics: a
+// short or corrupted packet is reported as Error::MalformedPacket.
+
+use crate::error::{Error, Result};
+use crate::packets::*;
+
+// Bootloader command constants
+pub const CMD_GET_INFO: u8 = 0x10;
+pub const CMD_SET_ADDRESS: u8 = 0x11;
+pub const CMD_GET_MAPPING: u8 = 0x12;
+pub const CMD_LOAD_BUFFER: u8 = 0x14;
+pub const CMD_READ_BUFFER: u8 = 0x15;
+pub const CMD_WRITE_FLASH: u8 = 0x18;
+pub const CMD_FLASH_STATUS: u8 = 0x19;
+pub const CMD_READ_FLASH: u8 = 0x1C;
+pub const CMD_RESET_INIT: u8 = 0xFF;
+pub const CMD_RESET: u8 = 0xF0;
+pub const CMD_ALLOFF: u8 = 0x01;
+pub const CMD_SYSOFF: u8 = 0x02;
+pub const CMD_SYSON: u8 = 0x03;
+pub const CMD_GETVBAT: u8 = 0x04;
+
+// First byte of every bootloader packet
+const HEADER: u8 = 0xff;
+
+fn malformed(message: String) -> Error {
+    Error::MalformedPacket(message)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+/// Bootloader command
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    GetInfo,
+    SetAddress([u8; 5]),
+    GetMapping,
+    LoadBuffer { page: u16, address: u16, data: Vec<u8> },
+    ReadBuffer { page: u16, address: u16 },
+    WriteFlash { buffer_page: u16, flash_page: u16, n_pages: u16 },
+    FlashStatus,
+    ReadFlash { page: u16, address: u16 },
+    ResetInit,
+    Reset,
+    AllOff,
+    SysOff,
+    SysOn,
+    GetVbat,
+}
+
+impl Command {
+    /// Command code
+    pub fn code(&self) -> u8 {
+        match self {
+            Command::GetInfo => CMD_GET_INFO,
+            Command::SetAddress(_) => CMD_SET_ADDRESS,
+            Command::GetMapping => CMD_GET_MAPPING,
+            Command::LoadBuffer { .. } => CMD_LOAD_BUFFER,
+            Command::ReadBuffer { .. } => CMD_READ_BUFFER,
+            Command::WriteFlash { .. } => CMD_WRITE_FLASH,
+            Command::FlashStatus => CMD_FLASH_STATUS,
+            Command::ReadFlash { .. } => CMD_READ_FLASH,
+            Command::ResetInit => CMD_RESET_INIT,
+            Command::Reset => CMD_RESET,
+            Command::AllOff => CMD_ALLOFF,
+            Command::SysOff => CMD_SYSOFF,
+            Command::SysOn => CMD_SYSON,
+            Command::GetVbat => CMD_GETVBAT,
+        }
+    }
+
+    /// Encode the command for a bootloader target
+    pub fn encode(&self, target: u8) -> Vec<u8> {
+        let mut packet = vec![HEADER, target, self.code()];
+        match self {
+            Command::SetAddress(address) => packet.extend_from_slice(address),
+            Command::LoadBuffer { page, address, data } => {
+                packet.extend_from_slice(&page.to_le_bytes());
+                packet.extend_from_slice(&address.to_le_bytes());
+                packet.extend_from_slice(data);
+            }
+            Command::ReadBuffer { page, address } | Command::ReadFlash { page, address } => {
+                packet.extend_from_slice(&page.to_le_bytes());
+                packet.extend_from_slice(&address.to_le_bytes());
+            }
+            Command::WriteFlash { buffer_page, flash_page, n_pages } => {
+                packet.extend_from_slice(&buffer_page.to_le_bytes());
+                packet.extend_from_slice(&flash_page.to_le_bytes());
+                packet.extend_from_slice(&n_pages.to_le_bytes());
+            }
+            _ => {}
+        }
+        packet
+    }
+
+    /// Decode a command packet, returns the target and the command
+    pub fn decode(bytes: &[u8]) -> Result<(u8, Command)> {
+        if bytes.len() < 3 || bytes[0] != HEADER {
+            return Err(malformed(format!("Invalid command packet {:02X?}", bytes)));
+        }
+        let (target, code, params) = (bytes[1], bytes[2], &bytes[3..]);
+        let check_params = |min_length: usize| {
+            if params.len() < min_length {
+                Err(malformed(format!(
+                    "Command 0x{:02X} too short: expected at least {} parameter bytes, got {}",
+                    code, min_length, params.len()
+                )))
+            } else {
+                Ok(())
+            }
+        };
+
+        let command = match code {
+            CMD_GET_INFO => Command::GetInfo,
+            CMD_SET_ADDRESS => {
+                check_params(5)?;
+                Command::SetAddress(params[..5].try_into().unwrap())
+            }
+            CMD_GET_MAPPING => Command::GetMapping,
+            CMD_LOAD_BUFFER => {
+                check_params(4)?;
+                Command::LoadBuffer { page: read_u16(params, 0), address: read_u16(params, 2), data: params[4..].to_vec() }
+            }
+            CMD_READ_BUFFER => {
+                check_params(4)?;
+                Command::ReadBuffer { page: read_u16(params, 0), address: read_u16(params, 2) }
+            }
+            CMD_WRITE_FLASH => {
+                check_params(6)?;
+                Command::WriteFlash {
+                    buffer_page: read_u16(params, 0),
+                    flash_page: read_u16(params, 2),
+                    n_pages: read_u16(params, 4),
+                }
+            }
+            CMD_FLASH_STATUS => Command::FlashStatus,
+            CMD_READ_FLASH => {
+                check_params(4)?;
+                Command::ReadFlash { page: read_u16(params, 0), address: read_u16(params, 2) }
+            }
+            CMD_RESET_INIT => Command::ResetInit,
+            CMD_RESET => Command::Reset,
+            CMD_ALLOFF => Command::AllOff,
+            CMD_SYSOFF => Command::SysOff,
+            CMD_SYSON => Command::SysOn,
+            CMD_GETVBAT => Command::GetVbat,
+            _ => return Err(malformed(format!("Unknown command 0x{:02X}", code))),
+        };
+
+        Ok((target, command))
+    }
+
+    /// Number of bytes at the start of the encoded command that are echoed in its response
+    pub fn echo_length(&self) -> usize {
+        match self {
+            Command::ReadBuffer { .. } | Command::ReadFlash { .. } => 7,
+            _ => 3,
+        }
+    }
+}
+
+/// Response of a bootloader to a command
+#[derive(Debug)]
+pub enum Response {
+    Info(InfoPacket),
+    Mapping(Vec<u8>),
+    BufferRead(BufferReadPacket),
+    FlashWrite(FlashWriteResponse),
+    FlashStatus(FlashStatusResponse),
+    FlashRead(FlashReadPacket),
+    Vbat(f32),
+}
+
+impl Response {
+    /// Decode a response packet received from `target`
+    pub fn decode(target: u8, bytes: &[u8]) -> Result<Response> {
+        if bytes.len() < 3 || bytes[0] != HEADER {
+            return Err(malformed(format!("Invalid response packet {:02X?}", bytes)));
+        }
+        if bytes[1] != target {
+            return Err(malformed(format!("Response from target 0x{:02X}, expected 0x{:02X}", bytes[1], target)));
+        }
+
+        // The packets decode from the command byte
+        let body = &bytes[2..];
+        let response = match bytes[2] {
+            CMD_GET_INFO => Response::Info(InfoPacket::from_bytes(body)?),
+            CMD_GET_MAPPING => Response::Mapping(bytes[3..].to_vec()),
+            CMD_READ_BUFFER => Response::BufferRead(BufferReadPacket::from_bytes(body)?),
+            CMD_WRITE_FLASH => Response::FlashWrite(FlashWriteResponse::from_bytes(body)?),
+            CMD_FLASH_STATUS => Response::FlashStatus(FlashStatusResponse::from_bytes(body)?),
+            CMD_READ_FLASH => Response::FlashRead(FlashReadPacket::from_bytes(body)?),
+            CMD_GETVBAT => {
+                if bytes.len() < 7 {
+                    return Err(malformed(format!("Invalid VBAT response length: {} bytes", bytes.len())));
+                }
+                Response::Vbat(f32::from_le_bytes(bytes[3..7].try_into().unwrap()))
+            }
+            code => return Err(malformed(format!("Unexpected response to command 0x{:02X}", code))),
+        };
+
+        Ok(response)
+    }
+
+    /// Encode the response as sent by `target`
+    pub fn encode(&self, target: u8) -> Vec<u8> {
+        let mut packet = vec![HEADER, target];
+        match self {
+            Response::Info(info) => {
+                packet.push(CMD_GET_INFO);
+                packet.extend_from_slice(&info.page_size().to_le_bytes());
+                packet.extend_from_slice(&info.n_buff_page().to_le_bytes());
+                packet.extend_from_slice(&info.n_flash_page().to_le_bytes());
+                packet.extend_from_slice(&info.flash_start().to_le_bytes());
+                packet.extend_from_slice(info.cpu_id());
+                packet.push(info.version());
+            }
+            Response::Mapping(mapping) => {
+                packet.push(CMD_GET_MAPPING);
+                packet.extend_from_slice(mapping);
+            }
+            Response::BufferRead(read) => {
+                packet.push(CMD_READ_BUFFER);
+                packet.extend_from_slice(&read.page.to_le_bytes());
+                packet.extend_from_slice(&read.address.to_le_bytes());
+                packet.extend_from_slice(&read.data);
+            }
+            Response::FlashWrite(status) => {
+                packet.push(CMD_WRITE_FLASH);
+                packet.extend_from_slice(&[status.done, status.error]);
+            }
+            Response::FlashStatus(status) => {
+                packet.push(CMD_FLASH_STATUS);
+                packet.extend_from_slice(&[status.done, status.error]);
+            }
+            Response::FlashRead(read) => {
+                packet.push(CMD_READ_FLASH);
+                packet.extend_from_slice(&read.page.to_le_bytes());
+                packet.extend_from_slice(&read.address.to_le_bytes());
+                packet.extend_from_slice(&read.data);
+            }
+            Response::Vbat(vbat) => {
+                packet.push(CMD_GETVBAT);
+                packet.extend_from_slice(&vbat.to_le_bytes());
+            }
+        }
+        packet
+    }
+
+    /// Name of the response, for error messages
+    fn name(&self) -> &'static str {
+        match self {
+            Response::Info(_) => "GET_INFO",
+            Response::Mapping(_) => "GET_MAPPING",
+            Response::BufferRead(_) => "READ_BUFFER",
+            Response::FlashWrite(_) => "WRITE_FLASH",
+            Response::FlashStatus(_) => "FLASH_STATUS",
+            Response::FlashRead(_) => "READ_FLASH",
+            Response::Vbat(_) => "GETVBAT",
+        }
+    }
+
+    /// Error for a response that is not the one expected by the command sent
+    pub fn unexpected(&self, expected: &str) -> Error {
+        malformed(format!("Expected {} response, got {}", expected, self.name()))
+    }
+}
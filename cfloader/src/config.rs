@@ -0,0 +1,104 @@
+// Retry and timeout policies of the bootloader link
+// The bootloader commands fall in a few classes that do not need the same patience: an info
+// query is answered at once, a buffer load is only acknowledged and a flash write takes up to a
+// second per page. Each class has its own policy, so that a long range setup can be very patient
+// while a production bench fails fast.
+
+use std::time::Duration;
+
+use crate::codec::{CMD_LOAD_BUFFER, CMD_READ_BUFFER, CMD_READ_FLASH, CMD_WRITE_FLASH};
+
+/// Class of a bootloader command, selecting its [RetryPolicy] in a [LinkConfig]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CommandClass {
+    /// GET_INFO, GET_MAPPING, FLASH_STATUS, GETVBAT and the commands without response
+    Info,
+    /// LOAD_BUFFER
+    BufferLoad,
+    /// WRITE_FLASH
+    FlashWrite,
+    /// READ_FLASH and READ_BUFFER
+    FlashRead,
+}
+
+impl CommandClass {
+    /// Class of an encoded `[0xff, target, command, ...]` packet
+    pub fn of(packet: &[u8]) -> CommandClass {
+        match packet.get(2) {
+            Some(&CMD_LOAD_BUFFER) => CommandClass::BufferLoad,
+            Some(&CMD_WRITE_FLASH) => CommandClass::FlashWrite,
+            Some(&CMD_READ_FLASH | &CMD_READ_BUFFER) => CommandClass::FlashRead,
+            _ => CommandClass::Info,
+        }
+    }
+}
+
+/// Retries, timeout and backoff of one class of commands
+///
+/// An attempt sends the command until it is acknowledged and waits for its response, each for
+/// up to `timeout`. The response timeout is shortened to the measured round-trip time of the
+/// link and lengthened by the backoff after a response timeout, see [crate::timing::LinkTiming].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Number of attempts after the first one
+    pub retries: usize,
+    /// Longest wait of one attempt
+    pub timeout: Duration,
+    /// Largest factor applied to the response timeout, doubled after each response timeout; 1
+    /// disables the backoff
+    pub backoff: u32,
+}
+
+impl RetryPolicy {
+    /// Longest time spent on all the attempts, before the backoff
+    ///
+    /// Saturates at [Duration::MAX] for policies retrying (nearly) forever.
+    pub fn total_timeout(&self) -> Duration {
+        let attempts = u32::try_from(self.retries.saturating_add(1)).unwrap_or(u32::MAX);
+        self.timeout.saturating_mul(attempts)
+    }
+}
+
+/// Retry and timeout policies of a bootloader link, one per [CommandClass]
+///
+/// Given to [crate::Bllink::new] or [crate::CFLoader::with_config].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkConfig {
+    /// Info queries and commands without response
+    pub info: RetryPolicy,
+    /// Buffer loads, an attempt waits for the acknowledgement of the packets and has no backoff
+    pub buffer_load: RetryPolicy,
+    /// Flash writes, an attempt waits for the end of the write for up to `timeout` per page
+    /// written and has no backoff; a write is only sent again when the bootloader has never
+    /// received it
+    pub flash_write: RetryPolicy,
+    /// Flash and RAM buffer reads
+    pub flash_read: RetryPolicy,
+    /// Maximum number of pipelined reads waiting for their response
+    pub pipeline_depth: usize,
+}
+
+impl LinkConfig {
+    /// Policy of a class of commands
+    pub fn policy(&self, class: CommandClass) -> RetryPolicy {
+        match class {
+            CommandClass::Info => self.info,
+            CommandClass::BufferLoad => self.buffer_load,
+            CommandClass::FlashWrite => self.flash_write,
+            CommandClass::FlashRead => self.flash_read,
+        }
+    }
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        LinkConfig {
+            info: RetryPolicy { retries: 10, timeout: Duration::from_millis(100), backoff: 4 },
+            buffer_load: RetryPolicy { retries: 10, timeout: Duration::from_millis(100), backoff: 1 },
+            // A write takes up to a second per page
+            flash_write: RetryPolicy { retries: 9, timeout: Duration::from_secs(2), backoff: 1 },
+            flash_read: RetryPolicy { retries: 10, timeout: Duration::from_millis(100), backoff: 4 },
+            pipeline_depth: 4,
+        }
+    }
+}
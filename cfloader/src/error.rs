@@ -0,0 +1,135 @@
+// Errors returned by the library
+// Each failure mode that a tool may want to react to (retry, ask the user to restart the
+// Crazyflie in bootloader mode, abort, ...) has its own variant.
+
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::packets::FlashError;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// No Crazyradio could be opened
+    NoRadio(crazyradio::Error),
+    /// Communication with the Crazyradio failed
+    Radio(crazyradio::Error),
+    /// The packet has never been acknowledged, the Crazyflie is likely not in bootloader mode or out of range
+    NoAck { timeout: Duration },
+    /// The packet has been acknowledged but no valid response has been received
+    ResponseTimeout { timeout: Duration },
+    /// The response does not correspond to the request, it is likely a stale packet
+    ResponseMismatch { expected: Vec<u8>, received: Vec<u8> },
+    /// A packet is too short, too long or inconsistent
+    MalformedPacket(String),
+    /// An argument given by the caller is out of the range accepted by the command
+    InvalidArgument(String),
+    /// The bootloader target is neither the nRF51 nor the STM32
+    InvalidTarget(u8),
+    /// The image to flash is empty
+    EmptyImage,
+    /// The image or read range does not fit in the flash area accessible through the bootloader
+    OutOfBounds { target: u8, address: u32, length: usize, valid_start: u32, valid_end: u32 },
+    /// The bootloader returned no data for part of a flash read
+    ShortRead { target: u8, address: u32, missing: usize },
+    /// The RAM buffer content read back differs from the data loaded, even after loading it again
+    BufferMismatch { target: u8, page: u16, address: u16 },
+    /// The bootloader reported an error when writing flash
+    Flash { target: u8, page: u16, error: FlashError },
+    /// The flash content differs from the image after it has been written
+    VerifyFailed { target: u8, pages: Vec<u16> },
+    /// The flashing plan has been made for another image or device
+    PlanMismatch(String),
+    /// Flashing failed and the firmware has been restored from a backup
+    RolledBack { error: Box<Error>, backup: PathBuf },
+    /// Flashing failed and restoring the firmware from a backup failed too, the device content
+    /// is unknown
+    RollbackFailed { error: Box<Error>, rollback: Box<Error>, backup: PathBuf },
+    /// The checkpoint file is invalid or has been made for another image or device
+    Checkpoint(String),
+    /// Error specific to a link implementation, for example a replay diverging from its capture
+    Link(String),
+    Io(std::io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::NoRadio(e) => write!(f, "No Crazyradio found: {}", e),
+            Error::Radio(e) => write!(f, "Radio error: {}", e),
+            Error::NoAck { timeout } => write!(f, "Timeout: No ACK received within {:?}", timeout),
+            Error::ResponseTimeout { timeout } => write!(f, "Timeout: No valid response received within {:?}", timeout),
+            Error::ResponseMismatch { expected, received } => write!(
+                f,
+                "Response mismatch: expected {:02X?} but got {:02X?} (stale packet detected)",
+                expected, received
+            ),
+            Error::MalformedPacket(message) => write!(f, "Malformed packet: {}", message),
+            Error::InvalidArgument(message) => write!(f, "Invalid argument: {}", message),
+            Error::InvalidTarget(target) => write!(f, "Invalid bootloader target: 0x{:02X}", target),
+            Error::EmptyImage => write!(f, "The image to flash is empty"),
+            Error::OutOfBounds { target, address, length, valid_start, valid_end } => write!(
+                f,
+                "{} bytes at 0x{:08X} out of the flash area 0x{:08X}..0x{:08X} of target 0x{:02X}",
+                length, address, valid_start, valid_end, target
+            ),
+            Error::ShortRead { target, address, missing } => write!(
+                f,
+                "Flash read of target 0x{:02X} returned no data at 0x{:08X}, {} bytes missing",
+                target, address, missing
+            ),
+            Error::BufferMismatch { target, page, address } => write!(
+                f,
+                "RAM buffer of target 0x{:02X} corrupted at page {} offset {}",
+                target, page, address
+            ),
+            Error::Flash { target, page, error } => write!(
+                f,
+                "Flash operation failed on target 0x{:02X} at page {}: {}",
+                target, page, error
+            ),
+            Error::VerifyFailed { target, pages } => write!(
+                f,
+                "Flash of target 0x{:02X} differs from the image in pages {:?}",
+                target, pages
+            ),
+            Error::PlanMismatch(message) => write!(f, "Flashing plan mismatch: {}", message),
+            Error::RolledBack { error, backup } => write!(
+                f,
+                "{}, previous firmware restored from {}",
+                error,
+                backup.display()
+            ),
+            Error::RollbackFailed { error, rollback, backup } => write!(
+                f,
+                "{}, restoring the previous firmware from {} failed: {}",
+                error,
+                backup.display(),
+                rollback
+            ),
+            Error::Checkpoint(message) => write!(f, "Checkpoint error: {}", message),
+            Error::Link(message) => write!(f, "Link error: {}", message),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::NoRadio(e) | Error::Radio(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::RolledBack { error, .. } | Error::RollbackFailed { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,184 @@
+// Fault injection for link robustness testing
+// Wraps any packet link and degrades it the way a busy 2.4GHz band does: lost packets,
+// lost acknowledgements, stale or truncated acknowledgement payloads and latency spikes.
+// Buffer loads can also be corrupted, to exercise the read-back of the RAM buffer.
+// Faults are drawn from a seeded generator so that a failing run can be reproduced.
+
+use std::time::Duration;
+
+use crate::bootloader::PAYLOAD_HEADER_SIZE;
+use crate::codec::CMD_LOAD_BUFFER;
+use crate::error::Result;
+use crate::link::{Ack, PacketLink};
+
+/// Probability of each fault, from 0.0 (never) to 1.0 (always)
+#[derive(Debug, Clone)]
+pub struct FaultConfig {
+    /// Seed of the fault generator
+    pub seed: u64,
+    /// Packet lost before reaching the receiver
+    pub drop_packet: f64,
+    /// Packet received but its acknowledgement lost
+    pub drop_ack: f64,
+    /// Acknowledgement carrying the previous payload again instead of the current one
+    pub stale_response: f64,
+    /// Acknowledgement payload cut short
+    pub truncate: f64,
+    /// Packet delayed by `latency`
+    pub latency_spike: f64,
+    pub latency: Duration,
+    /// LOAD_BUFFER packet received with one data bit flipped
+    pub corrupt_load: f64,
+}
+
+impl FaultConfig {
+    /// No fault injected
+    pub fn none(seed: u64) -> Self {
+        FaultConfig {
+            seed,
+            drop_packet: 0.0,
+            drop_ack: 0.0,
+            stale_response: 0.0,
+            truncate: 0.0,
+            latency_spike: 0.0,
+            latency: Duration::ZERO,
+            corrupt_load: 0.0,
+        }
+    }
+
+    /// Lose `rate` of the exchanges, split evenly between lost packets and lost acknowledgements
+    pub fn packet_loss(seed: u64, rate: f64) -> Self {
+        FaultConfig {
+            drop_packet: rate / 2.0,
+            drop_ack: rate / 2.0,
+            ..FaultConfig::none(seed)
+        }
+    }
+}
+
+/// Number of faults injected so far
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FaultStats {
+    pub packets: usize,
+    pub dropped_packets: usize,
+    pub dropped_acks: usize,
+    pub stale_responses: usize,
+    pub truncated: usize,
+    pub latency_spikes: usize,
+    pub corrupted_loads: usize,
+}
+
+// xorshift64* generator, good enough to draw faults and independent of any external crate
+struct FaultRng(u64);
+
+impl FaultRng {
+    fn new(seed: u64) -> Self {
+        // splitmix64 spreads close seeds over the whole state, which must never be zero
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        FaultRng((z ^ (z >> 31)).max(1))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        // 53 random bits give a uniform value in [0, 1)
+        let value = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        probability > 0.0 && value < probability
+    }
+
+    fn below(&mut self, max: usize) -> usize {
+        (self.next_u64() % max as u64) as usize
+    }
+}
+
+/// Packet link wrapper injecting faults in the exchanges of the wrapped link
+pub struct FaultyLink<P: PacketLink> {
+    link: P,
+    config: FaultConfig,
+    rng: FaultRng,
+    last_payload: Vec<u8>,
+    stats: FaultStats,
+}
+
+impl<P: PacketLink> FaultyLink<P> {
+    pub fn new(link: P, config: FaultConfig) -> Self {
+        let rng = FaultRng::new(config.seed);
+        FaultyLink { link, config, rng, last_payload: Vec::new(), stats: FaultStats::default() }
+    }
+
+    /// Faults injected so far
+    pub fn stats(&self) -> &FaultStats {
+        &self.stats
+    }
+
+    /// Get the wrapped link
+    pub fn inner(&self) -> &P {
+        &self.link
+    }
+
+    /// Get the wrapped link mutably
+    pub fn inner_mut(&mut self) -> &mut P {
+        &mut self.link
+    }
+}
+
+impl<P: PacketLink> PacketLink for FaultyLink<P> {
+    async fn send_packet(&mut self, data: &[u8]) -> Result<Ack> {
+        let lost = Ack { received: false, payload: Vec::new() };
+        self.stats.packets += 1;
+
+        if self.rng.chance(self.config.latency_spike) {
+            self.stats.latency_spikes += 1;
+            tokio::time::sleep(self.config.latency).await;
+        }
+
+        if self.rng.chance(self.config.drop_packet) {
+            self.stats.dropped_packets += 1;
+            return Ok(lost);
+        }
+
+        let mut corrupted;
+        let data = if data.get(2) == Some(&CMD_LOAD_BUFFER) && data.len() > PAYLOAD_HEADER_SIZE && self.rng.chance(self.config.corrupt_load) {
+            self.stats.corrupted_loads += 1;
+            corrupted = data.to_vec();
+            let index = PAYLOAD_HEADER_SIZE + self.rng.below(data.len() - PAYLOAD_HEADER_SIZE);
+            corrupted[index] ^= 1 << self.rng.below(8);
+            &corrupted[..]
+        } else {
+            data
+        };
+
+        let mut ack = self.link.send_packet(data).await?;
+        if !ack.received {
+            return Ok(ack);
+        }
+
+        if self.rng.chance(self.config.drop_ack) {
+            self.stats.dropped_acks += 1;
+            return Ok(lost);
+        }
+
+        if self.rng.chance(self.config.stale_response) {
+            self.stats.stale_responses += 1;
+            ack.payload = self.last_payload.clone();
+        } else if !ack.payload.is_empty() && self.rng.chance(self.config.truncate) {
+            self.stats.truncated += 1;
+            let len = self.rng.below(ack.payload.len());
+            ack.payload.truncate(len);
+        }
+
+        self.last_payload = ack.payload.clone();
+        Ok(ack)
+    }
+
+    fn max_packet_size(&self) -> usize {
+        self.link.max_packet_size()
+    }
+}
@@ -1,8 +1,26 @@
 mod bllink;
 pub mod bootloader;
+pub mod checkpoint;
 mod cfloader;
+pub mod codec;
+pub mod config;
+mod error;
+pub mod fault;
+pub mod link;
+mod options;
 pub mod packets;
+pub mod plan;
+pub mod record;
+pub mod sim;
+pub mod timing;
+pub mod verify;
 
-pub use bllink::Bllink;
-pub use bootloader::Bootloader;
+pub use bllink::{Bllink, RadioLink};
+pub use bootloader::{Bootloader, PayloadSize};
 pub use cfloader::CFLoader;
+pub use config::{LinkConfig, RetryPolicy};
+pub use error::{Error, Result};
+pub use link::{Link, PacketLink};
+pub use options::{BufferVerify, FlashOptions, PartialPage};
+pub use plan::FlashPlan;
+pub use verify::{VerifyMode, VerifyReport};
@@ -0,0 +1,117 @@
+// Transport abstraction used by the bootloader
+// The bootloader protocol only needs a few primitives: send a packet and wait for a
+// response, send a packet and wait for a response matching part of the request, and
+// send a packet without expecting any response, retrying or not when it is not
+// acknowledged. Anything that can carry bootloader packets (Crazyradio, USB, network
+// relay, simulator, ...) can implement this trait.
+
+use std::future::Future;
+
+use crate::config::LinkConfig;
+use crate::error::Result;
+
+// Largest payload of a Crazyradio packet
+pub(crate) const MAX_RADIO_PACKET_SIZE: usize = 32;
+
+/// Packet transport to a Crazyflie bootloader
+///
+/// [crate::Bllink] is the Crazyradio implementation of this trait.
+pub trait Link: Send {
+    /// Retry and timeout policies of the commands sent on the link
+    fn config(&self) -> &LinkConfig;
+
+    /// Change the retry and timeout policies of the commands sent on the link
+    fn set_config(&mut self, config: LinkConfig);
+
+    /// Send a packet as request, expect one packet as response
+    ///
+    /// The response must start with the request bytes. The request is retried following the
+    /// policy of its command in [Link::config].
+    fn request(&mut self, data: &[u8]) -> impl Future<Output = Result<Vec<u8>>> + Send;
+
+    /// Send a packet as request, expect one packet as response. The first `match_length` bytes of the response must match the request
+    fn request_match_response(&mut self, data: &[u8], match_length: usize) -> impl Future<Output = Result<Vec<u8>>> + Send;
+
+    /// Send a packet as request, expect one packet as response matching the request and accepted by `accept`
+    ///
+    /// A response rejected by `accept`, for example cut short or corrupted on the way, is not an
+    /// answer. Implementations send the request again within the attempts of its policy, as for a
+    /// lost response, and return the last response when none is accepted. The default
+    /// implementation returns the first response matching the request.
+    fn request_accepted_response<F>(&mut self, data: &[u8], match_length: usize, accept: F) -> impl Future<Output = Result<Vec<u8>>> + Send
+    where
+        F: Fn(&[u8]) -> bool + Send,
+    {
+        async move {
+            let _ = accept;
+            self.request_match_response(data, match_length).await
+        }
+    }
+
+    /// Largest packet carried by the link, 32 bytes for a Crazyradio
+    fn max_packet_size(&self) -> usize {
+        MAX_RADIO_PACKET_SIZE
+    }
+
+    /// Send a packet as request, expect no response
+    fn send(&mut self, data: &[u8]) -> impl Future<Output = Result<()>> + Send;
+
+    /// Send a packet once, expect no response
+    ///
+    /// The packet is not sent again if it is not acknowledged: a lost acknowledgement does not mean
+    /// that the packet has been lost. This is used for commands that must not be executed twice.
+    /// Returns true if the packet has been acknowledged.
+    fn send_once(&mut self, data: &[u8]) -> impl Future<Output = Result<bool>> + Send;
+
+    /// Send several packets, expect no response
+    ///
+    /// The packets can be sent in any order and more than once, as buffer loads. Implementations
+    /// can send them back-to-back and only send again the ones not acknowledged, the default
+    /// implementation sends them one at a time.
+    fn send_pipelined(&mut self, packets: &[Vec<u8>]) -> impl Future<Output = Result<()>> + Send {
+        async move {
+            for packet in packets {
+                self.send(packet).await?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Send several requests, expect one response to each
+    ///
+    /// Each response is matched to its request by their first `match_length` bytes, the responses
+    /// are returned in the order of the requests. Implementations can keep several requests in
+    /// flight, the default implementation sends them one at a time.
+    fn request_pipelined(&mut self, requests: &[Vec<u8>], match_length: usize) -> impl Future<Output = Result<Vec<Vec<u8>>>> + Send {
+        async move {
+            let mut responses = Vec::with_capacity(requests.len());
+            for request in requests {
+                responses.push(self.request_match_response(request, match_length).await?);
+            }
+            Ok(responses)
+        }
+    }
+}
+
+/// Acknowledgement of a packet sent on a [PacketLink]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ack {
+    /// True if the packet has been acknowledged by the receiver
+    pub received: bool,
+    /// Payload carried by the acknowledgement, the bootloader uses it to send responses
+    pub payload: Vec<u8>,
+}
+
+/// Low level, unreliable packet transport
+///
+/// One call sends one packet and reports if it has been acknowledged. Retries and response
+/// polling are implemented on top of it by [crate::Bllink].
+pub trait PacketLink: Send {
+    /// Send one packet and return the received acknowledgement
+    fn send_packet(&mut self, data: &[u8]) -> impl Future<Output = Result<Ack>> + Send;
+
+    /// Largest packet carried by the link, 32 bytes for a Crazyradio
+    fn max_packet_size(&self) -> usize {
+        MAX_RADIO_PACKET_SIZE
+    }
+}
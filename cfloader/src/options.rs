@@ -0,0 +1,76 @@
+// Options of the flashing algorithm
+// The defaults flash the image as it is, page after page. The safety options (buffer
+// read-back, safe page order, checkpoint, backup) cost time, the speed options (page
+// deduplication, double buffering, delta flashing) are opt-in as they depend on the image or
+// the bootloader.
+
+use std::path::PathBuf;
+
+/// How the RAM buffer is checked with READ_BUFFER before it is written to flash
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BufferVerify {
+    /// The buffer is written to flash as loaded
+    #[default]
+    Off,
+    /// Every loaded segment is read back
+    Full,
+    /// One loaded segment out of `n` is read back, a mismatch triggers a full read-back
+    Sampled(usize),
+}
+
+/// Content written around an image that does not start or end on a page boundary
+///
+/// Flash is written by whole pages, the bytes of the first and last pages that are not part
+/// of the image are written with this content.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PartialPage {
+    /// The current flash content is read and written back (read-modify-write)
+    #[default]
+    Preserve,
+    /// 0xFF, the value of erased flash, no read needed
+    Erase,
+}
+
+/// Options of [crate::CFLoader::flash_image_with_options] and [crate::CFLoader::flash_and_verify]
+#[derive(Debug, Clone)]
+pub struct FlashOptions {
+    /// Read-back of the RAM buffer before each flash write, corrupted segments are loaded again
+    pub buffer_verify: BufferVerify,
+    /// Content of the first and last pages around an image that is not page aligned
+    pub partial_pages: PartialPage,
+    /// Load the pages with identical content, like erased (0xFF) padding, in the RAM buffer once
+    /// and write them from there, see [crate::FlashPlan::dedupe_pages]
+    pub dedupe_pages: bool,
+    /// Write from one half of the RAM buffer while the next pages are loaded in the other half,
+    /// when the bootloader processes commands during flash writes
+    pub double_buffer: bool,
+    /// Read the flash first and only load and write the pages that differ from the image
+    pub delta: bool,
+    /// Erase the first page of the image, holding the vector table of a firmware, before writing
+    /// the other pages and only write it once they have been verified
+    pub safe_order: bool,
+    /// Checkpoint file updated after each flash write and removed once the image is flashed,
+    /// see [crate::CFLoader::resume_flash]
+    pub checkpoint: Option<PathBuf>,
+    /// Directory where the firmware region is saved before flashing, the backup is flashed back
+    /// if flashing fails, see [crate::CFLoader::backup]
+    pub backup: Option<PathBuf>,
+    /// Number of times the pages failing verification are flashed again by `flash_and_verify`
+    pub repair_attempts: usize,
+}
+
+impl Default for FlashOptions {
+    fn default() -> Self {
+        FlashOptions {
+            buffer_verify: BufferVerify::Off,
+            partial_pages: PartialPage::Preserve,
+            dedupe_pages: false,
+            double_buffer: false,
+            delta: false,
+            safe_order: false,
+            checkpoint: None,
+            backup: None,
+            repair_attempts: 3,
+        }
+    }
+}
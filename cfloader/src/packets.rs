@@ -1,5 +1,18 @@
 use std::{fmt::Debug, fmt::Display};
 
+use crate::error::{Error, Result};
+
+// Check that a packet is long enough before decoding it
+fn check_length(name: &str, bytes: &[u8], min_length: usize) -> Result<()> {
+    if bytes.len() < min_length {
+        return Err(Error::MalformedPacket(format!(
+            "Invalid {} length: expected at least {} bytes, got {}",
+            name, min_length, bytes.len()
+        )));
+    }
+    Ok(())
+}
+
 // Info packet structure:
 // [0xff, target, 0x10, pageSize, nBuffPage, nFlashPage, flashStart, cpuId, version]
 //
@@ -20,18 +33,32 @@ pub struct InfoPacket {
 }
 
 impl InfoPacket {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        if bytes.len() < 22 {
-            panic!("Invalid InfoPacket length: expected at least 22 bytes, got {}", bytes.len());
-        }
+    pub fn new(page_size: u16, n_buff_page: u16, n_flash_page: u16, flash_start: u16, version: u8) -> Result<Self> {
+        InfoPacket { page_size, n_buff_page, n_flash_page, flash_start, cpu_id: [0; 12], version }.checked()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        check_length("InfoPacket", bytes, 22)?;
         InfoPacket {
             page_size: u16::from_le_bytes([bytes[1], bytes[2]]),
             n_buff_page: u16::from_le_bytes([bytes[3], bytes[4]]),
             n_flash_page: u16::from_le_bytes([bytes[5], bytes[6]]),
             flash_start: u16::from_le_bytes([bytes[7], bytes[8]]),
-            cpu_id: [bytes[9], bytes[10], bytes[11], bytes[12], bytes[13], bytes[14], bytes[15], bytes[16], bytes[17], bytes[18], bytes[19], bytes[20]],
+            cpu_id: bytes[9..21].try_into().unwrap(),
             version: bytes[21],
         }
+        .checked()
+    }
+
+    // Reject a flash geometry that cannot be planned, typically from a corrupted packet
+    fn checked(self) -> Result<Self> {
+        if self.page_size == 0 || self.n_buff_page == 0 || self.flash_start > self.n_flash_page {
+            return Err(Error::MalformedPacket(format!(
+                "Invalid InfoPacket geometry: page_size {}, n_buff_page {}, n_flash_page {}, flash_start {}",
+                self.page_size, self.n_buff_page, self.n_flash_page, self.flash_start
+            )));
+        }
+        Ok(self)
     }
 
     pub fn page_size(&self) -> u16 {
@@ -50,9 +77,34 @@ impl InfoPacket {
         self.flash_start
     }
 
+    pub fn cpu_id(&self) -> &[u8; 12] {
+        &self.cpu_id
+    }
+
     pub fn version(&self) -> u8 {
         self.version
     }
+
+    // Check that `length` bytes at `address` are in the flash, `target` is only used in the error
+    //
+    // Reads can access the whole flash, writes must stay after `flash_start` as the pages before
+    // hold the bootloader.
+    pub(crate) fn check_bounds(&self, target: u8, address: u32, length: usize, write: bool) -> Result<()> {
+        let page_size = self.page_size as u64;
+        let valid_start = if write { self.flash_start as u64 * page_size } else { 0 };
+        let valid_end = self.n_flash_page as u64 * page_size;
+
+        if (address as u64) < valid_start || address as u64 + length as u64 > valid_end {
+            return Err(Error::OutOfBounds {
+                target,
+                address,
+                length,
+                valid_start: valid_start as u32,
+                valid_end: valid_end as u32,
+            });
+        }
+        Ok(())
+    }
 }
 
 impl Debug for InfoPacket {
@@ -83,15 +135,13 @@ pub struct BufferReadPacket {
 }
 
 impl BufferReadPacket {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        if bytes.len() < 5 {
-            panic!("Invalid BufferReadPacket length");
-        }
-        BufferReadPacket {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        check_length("BufferReadPacket", bytes, 5)?;
+        Ok(BufferReadPacket {
             page: u16::from_le_bytes([bytes[1], bytes[2]]),
             address: u16::from_le_bytes([bytes[3], bytes[4]]),
             data: bytes[5..].to_vec(),
-        }
+        })
     }
 }
 
@@ -112,14 +162,12 @@ pub struct FlashWriteResponse {
 }
 
 impl FlashWriteResponse {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        if bytes.len() < 3 {
-            panic!("Invalid FlashWriteResponse length");
-        }
-        FlashWriteResponse {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        check_length("FlashWriteResponse", bytes, 3)?;
+        Ok(FlashWriteResponse {
             done: bytes[1],
             error: bytes[2],
-        }
+        })
     }
 
     pub fn is_done(&self) -> bool {
@@ -155,15 +203,13 @@ pub struct FlashReadPacket {
 }
 
 impl FlashReadPacket {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        if bytes.len() < 5 {
-            panic!("Invalid FlashReadPacket length");
-        }
-        FlashReadPacket {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        check_length("FlashReadPacket", bytes, 5)?;
+        Ok(FlashReadPacket {
             page: u16::from_le_bytes([bytes[1], bytes[2]]),
             address: u16::from_le_bytes([bytes[3], bytes[4]]),
             data: bytes[5..].to_vec(),
-        }
+        })
     }
 }
 
@@ -180,10 +226,12 @@ impl Debug for FlashReadPacket {
 // Error codes enum for flash operations
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum FlashError {
-    NoError = 0,
-    AddressOutOfBounds = 1,
-    FlashEraseFailed = 2,
-    FlashProgrammingFailed = 3,
+    NoError,
+    AddressOutOfBounds,
+    FlashEraseFailed,
+    FlashProgrammingFailed,
+    /// Error code not known by this library, never considered a success
+    Unknown(u8),
 }
 
 impl From<u8> for FlashError {
@@ -193,7 +241,19 @@ impl From<u8> for FlashError {
             1 => FlashError::AddressOutOfBounds,
             2 => FlashError::FlashEraseFailed,
             3 => FlashError::FlashProgrammingFailed,
-            _ => FlashError::NoError, // Default to no error for unknown codes
+            code => FlashError::Unknown(code),
+        }
+    }
+}
+
+impl From<FlashError> for u8 {
+    fn from(error: FlashError) -> Self {
+        match error {
+            FlashError::NoError => 0,
+            FlashError::AddressOutOfBounds => 1,
+            FlashError::FlashEraseFailed => 2,
+            FlashError::FlashProgrammingFailed => 3,
+            FlashError::Unknown(code) => code,
         }
     }
 }
@@ -205,6 +265,7 @@ impl Display for FlashError {
             FlashError::AddressOutOfBounds => write!(f, "Addresses are outside of authorized boundaries"),
             FlashError::FlashEraseFailed => write!(f, "Flash erase failed"),
             FlashError::FlashProgrammingFailed => write!(f, "Flash programming failed"),
+            FlashError::Unknown(code) => write!(f, "Unknown flash error code {}", code),
         }
     }
 }
\ No newline at end of file
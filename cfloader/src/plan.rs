@@ -0,0 +1,432 @@
+// Flashing plan
+// A flashing is planned from the image, the bootloader info and the flash options before
+// anything is sent: the plan lists every buffer load and flash write, so that the chunking can
+// be checked without hardware and shown to the user by a dry-run.
+//
+// The plan works on the page aligned image: the partial first and last pages of an image are
+// completed with `head` bytes before it and `tail` bytes after it.
+
+use std::fmt::Display;
+use std::ops::Range;
+use std::time::Duration;
+
+use crate::bootloader::PayloadSize;
+use crate::checkpoint::image_hash;
+use crate::error::{Error, Result};
+use crate::options::{BufferVerify, FlashOptions, PartialPage};
+use crate::packets::InfoPacket;
+
+// Rough timing used for the duration estimate: one radio packet and its acknowledgement, and
+// the erase and programming of one flash page
+const PACKET_TIME: Duration = Duration::from_millis(1);
+const PAGE_WRITE_TIME: Duration = Duration::from_millis(25);
+
+/// One LOAD_BUFFER packet
+#[derive(Debug, Clone, PartialEq)]
+pub struct BufferLoad {
+    pub buffer_page: u16,
+    /// Offset in the buffer page
+    pub address: u16,
+    /// Bytes loaded, as a range of the data of the step
+    pub range: Range<usize>,
+}
+
+/// One flash write and the buffer loads preparing it
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageWrite {
+    pub loads: Vec<BufferLoad>,
+    pub buffer_page: u16,
+    pub flash_page: u16,
+    pub n_pages: u16,
+}
+
+impl PageWrite {
+    // Load `range` of the data in the buffer from `buffer_page` and write it to flash at `flash_page`
+    fn new(range: Range<usize>, buffer_page: u16, flash_page: u16, page_size: usize, load_size: usize) -> Self {
+        let n_pages = range.len().div_ceil(page_size) as u16;
+        PageWrite { loads: buffer_loads(range, buffer_page, page_size, load_size), buffer_page, flash_page, n_pages }
+    }
+
+    /// Range of the data loaded, empty if the write only uses pages already in the buffer
+    pub fn range(&self) -> Range<usize> {
+        match (self.loads.first(), self.loads.last()) {
+            (Some(first), Some(last)) => first.range.start..last.range.end,
+            _ => 0..0,
+        }
+    }
+
+    /// Buffer pages written to flash
+    pub fn buffer_pages(&self) -> Range<u16> {
+        self.buffer_page..self.buffer_page + self.n_pages
+    }
+}
+
+// LOAD_BUFFER packets of `load_size` bytes loading `range` of the data in the buffer from `buffer_page`
+fn buffer_loads(range: Range<usize>, buffer_page: u16, page_size: usize, load_size: usize) -> Vec<BufferLoad> {
+    let mut loads = Vec::new();
+    let mut offset = range.start;
+    while offset < range.end {
+        let buffer_offset = offset - range.start;
+        let page_end = offset + page_size - buffer_offset % page_size;
+        let end = (offset + load_size).min(page_end).min(range.end);
+        loads.push(BufferLoad {
+            buffer_page: buffer_page + (buffer_offset / page_size) as u16,
+            address: (buffer_offset % page_size) as u16,
+            range: offset..end,
+        });
+        offset = end;
+    }
+    loads
+}
+
+// Content of the RAM buffer pages while planning, to find the pages already loaded
+struct BufferPages {
+    page_size: usize,
+    load_size: usize,
+    content: Vec<Option<Vec<u8>>>,
+    // Last write using each buffer page, the least recently used page is loaded first
+    last_use: Vec<usize>,
+    writes: usize,
+}
+
+impl BufferPages {
+    fn new(n_buff_page: u16, page_size: usize, load_size: usize) -> Self {
+        let n_buff_page = n_buff_page as usize;
+        BufferPages { page_size, load_size, content: vec![None; n_buff_page], last_use: vec![0; n_buff_page], writes: 0 }
+    }
+
+    fn find(&self, content: &[u8], region: &Range<usize>) -> Option<usize> {
+        region.clone().find(|&buffer_page| self.content[buffer_page].as_deref() == Some(content))
+    }
+
+    // Plan the writes of `data[range]` to flash from `flash_page` using the buffer pages of
+    // `region`, the pages already in the region are not loaded again
+    fn write(&mut self, data: &[u8], range: Range<usize>, flash_page: u16, region: Range<usize>) -> Vec<PageWrite> {
+        let page_size = self.page_size;
+        self.writes += 1;
+        let pages: Vec<Range<usize>> = range.clone().step_by(page_size).map(|start| start..(start + page_size).min(range.end)).collect();
+
+        // Pages already in the buffer are kept first so that loading the others cannot evict them
+        let mut mapping: Vec<Option<usize>> = pages.iter().map(|page| self.find(&data[page.clone()], &region)).collect();
+        let mut in_use = vec![false; self.content.len()];
+        for &buffer_page in mapping.iter().flatten() {
+            in_use[buffer_page] = true;
+        }
+
+        let mut loads = Vec::new();
+        for (i, page) in pages.iter().enumerate() {
+            if mapping[i].is_some() {
+                continue;
+            }
+
+            // Identical to a page loaded for this write, or loaded in the same buffer page as
+            // without deduplication when it is free so that the writes can be merged
+            let content = &data[page.clone()];
+            let buffer_page = match self.find(content, &region) {
+                Some(buffer_page) => buffer_page,
+                None => {
+                    let buffer_page = if !in_use[region.start + i] {
+                        region.start + i
+                    } else {
+                        region.clone().filter(|&b| !in_use[b]).min_by_key(|&b| self.last_use[b]).expect("a write never uses more pages than its buffer region")
+                    };
+                    self.content[buffer_page] = Some(content.to_vec());
+                    loads.extend(buffer_loads(page.clone(), buffer_page as u16, page_size, self.load_size));
+                    buffer_page
+                }
+            };
+            in_use[buffer_page] = true;
+            mapping[i] = Some(buffer_page);
+        }
+
+        // Consecutive buffer pages written to consecutive flash pages are written together
+        let mut writes: Vec<PageWrite> = Vec::new();
+        for (i, buffer_page) in mapping.into_iter().flatten().enumerate() {
+            self.last_use[buffer_page] = self.writes;
+            let buffer_page = buffer_page as u16;
+            let page = flash_page + i as u16;
+            match writes.last_mut() {
+                Some(write) if write.buffer_page + write.n_pages == buffer_page && write.flash_page + write.n_pages == page => write.n_pages += 1,
+                _ => writes.push(PageWrite { loads: Vec::new(), buffer_page, flash_page: page, n_pages: 1 }),
+            }
+        }
+        // All the pages are loaded before the first write
+        if let Some(first) = writes.first_mut() {
+            first.loads = loads;
+        }
+        writes
+    }
+}
+
+/// Step of a flashing plan, executed in order
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlanStep {
+    /// Write erased pages (0xFF), the load ranges are offsets in the erased pages
+    Erase(PageWrite),
+    /// Write part of the image, the load ranges are offsets in the page aligned image
+    Write(PageWrite),
+    /// Read back part of the page aligned image, the flashing stops if it differs
+    Verify(Range<usize>),
+}
+
+/// Every operation of the flashing of an image
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlashPlan {
+    pub target: u8,
+    /// Page aligned flash address of the first page
+    pub address: u32,
+    /// Number of bytes before the image in its first page
+    pub head: usize,
+    /// Number of bytes after the image in its last page
+    pub tail: usize,
+    pub image_length: usize,
+    /// Hash of the image the plan has been made for, see [crate::checkpoint::image_hash]
+    pub image_hash: u64,
+    pub page_size: usize,
+    pub n_buff_page: u16,
+    pub payload_size: PayloadSize,
+    /// Each write uses one half of the buffer pages, the other half is loaded meanwhile
+    pub double_buffer: bool,
+    /// Content written in the head and tail bytes
+    pub partial_pages: PartialPage,
+    pub buffer_verify: BufferVerify,
+    pub steps: Vec<PlanStep>,
+}
+
+impl FlashPlan {
+    /// Plan the flashing of a whole image at `start_address`
+    ///
+    /// The image is checked against the flash geometry of the target. The loads carry
+    /// `payload_size.load` bytes, see [crate::CFLoader::payload_size].
+    pub fn new(target: u8, start_address: u32, image: &[u8], info: &InfoPacket, payload_size: PayloadSize, options: &FlashOptions) -> Result<Self> {
+        let page_size = info.page_size() as usize;
+        let head = start_address as usize % page_size;
+        let length = (head + image.len()).next_multiple_of(page_size);
+        Self::with_runs(target, start_address, image, info, payload_size, options, &[Range { start: 0, end: length }])
+    }
+
+    /// Plan the flashing of parts of an image
+    ///
+    /// `runs` are ranges of the page aligned image, for example the pages found to differ by a
+    /// delta flashing.
+    pub fn with_runs(target: u8, start_address: u32, image: &[u8], info: &InfoPacket, payload_size: PayloadSize, options: &FlashOptions, runs: &[Range<usize>]) -> Result<Self> {
+        let image_length = image.len();
+        if image_length == 0 {
+            return Err(Error::EmptyImage);
+        }
+        info.check_bounds(target, start_address, image_length, true)?;
+
+        let page_size = info.page_size() as usize;
+        let head = start_address as usize % page_size;
+        let tail = (page_size - (head + image_length) % page_size) % page_size;
+        let address = start_address - head as u32;
+        let length = head + image_length + tail;
+        let start_page = (address / page_size as u32) as u16;
+
+        let mut plan = FlashPlan {
+            target,
+            address,
+            head,
+            tail,
+            image_length,
+            image_hash: image_hash(image),
+            page_size,
+            n_buff_page: info.n_buff_page(),
+            payload_size,
+            double_buffer: options.double_buffer && info.n_buff_page() >= 2,
+            partial_pages: options.partial_pages,
+            buffer_verify: options.buffer_verify,
+            steps: Vec::new(),
+        };
+
+        let mut runs: Vec<Range<usize>> = runs.iter().map(|run| run.start..run.end.min(length)).filter(|run| !run.is_empty()).collect();
+
+        // With safe ordering the first page of the image, holding the vector table of a firmware,
+        // is erased first and only written once the rest of the image has been verified: an
+        // interrupted flashing leaves a device that stays in the bootloader instead of booting
+        // a half-written firmware.
+        let defer_first_page = options.safe_order && runs.iter().any(|run| run.end > page_size);
+        if defer_first_page {
+            runs = runs
+                .into_iter()
+                .filter_map(|run| {
+                    let start = run.start.max(page_size);
+                    (start < run.end).then_some(start..run.end)
+                })
+                .collect();
+            let buffer_page = plan.next_buffer_region();
+            plan.steps.push(PlanStep::Erase(PageWrite::new(0..page_size, buffer_page, start_page, page_size, payload_size.load)));
+        }
+
+        // Each run is written buffer by buffer
+        let buffer_size = page_size * plan.buffer_region_size();
+        for run in runs {
+            let mut offset = run.start;
+            while offset < run.end {
+                let end = (offset + buffer_size).min(run.end);
+                let flash_page = start_page + (offset / page_size) as u16;
+                let buffer_page = plan.next_buffer_region();
+                plan.steps.push(PlanStep::Write(PageWrite::new(offset..end, buffer_page, flash_page, page_size, payload_size.load)));
+                offset = end;
+            }
+        }
+
+        if defer_first_page {
+            plan.steps.push(PlanStep::Verify(page_size..length));
+            let buffer_page = plan.next_buffer_region();
+            plan.steps.push(PlanStep::Write(PageWrite::new(0..page_size, buffer_page, start_page, page_size, payload_size.load)));
+        }
+
+        Ok(plan)
+    }
+
+    // Number of buffer pages used by one write
+    fn buffer_region_size(&self) -> usize {
+        if self.double_buffer { self.n_buff_page as usize / 2 } else { self.n_buff_page as usize }
+    }
+
+    // First buffer page of the next write, the halves of the buffer alternate with double buffering
+    fn next_buffer_region(&self) -> u16 {
+        let writes = self.writes().count();
+        if self.double_buffer && writes % 2 == 1 { self.n_buff_page / 2 } else { 0 }
+    }
+
+    /// Load identical pages in the RAM buffer only once
+    ///
+    /// The pages with the same content as a page already in the buffer, typically erased (0xFF)
+    /// padding, are written from that buffer page instead of being loaded again: an additional
+    /// write command is much faster than the 41 packets loading a 1 KB page. The RAM buffer is
+    /// kept between writes, pages loaded for a previous write are reused too. With double
+    /// buffering a write only uses pages of its half of the buffer.
+    pub fn dedupe_pages(&mut self, aligned_image: &[u8]) {
+        let erased = vec![0xff; self.page_size];
+        let mut buffer = BufferPages::new(self.n_buff_page, self.page_size, self.payload_size.load);
+        let region_size = self.buffer_region_size();
+
+        for step in std::mem::take(&mut self.steps) {
+            match step {
+                PlanStep::Erase(write) => {
+                    let length = write.n_pages as usize * self.page_size;
+                    let region = write.buffer_page as usize..write.buffer_page as usize + region_size;
+                    for write in buffer.write(&erased.repeat(write.n_pages as usize), 0..length, write.flash_page, region) {
+                        self.steps.push(PlanStep::Erase(write));
+                    }
+                }
+                PlanStep::Write(write) => {
+                    let region = write.buffer_page as usize..write.buffer_page as usize + region_size;
+                    for write in buffer.write(aligned_image, write.range(), write.flash_page, region) {
+                        self.steps.push(PlanStep::Write(write));
+                    }
+                }
+                step => self.steps.push(step),
+            }
+        }
+    }
+
+    /// Size of the page aligned image
+    pub fn aligned_length(&self) -> usize {
+        self.head + self.image_length + self.tail
+    }
+
+    /// Flash writes of the plan, erases included
+    pub fn writes(&self) -> impl Iterator<Item = &PageWrite> {
+        self.steps.iter().filter_map(|step| match step {
+            PlanStep::Erase(write) | PlanStep::Write(write) => Some(write),
+            PlanStep::Verify(_) => None,
+        })
+    }
+
+    /// Number of LOAD_BUFFER packets
+    pub fn load_count(&self) -> usize {
+        self.writes().map(|write| write.loads.len()).sum()
+    }
+
+    /// Number of bytes loaded in the RAM buffer
+    pub fn bytes_loaded(&self) -> usize {
+        self.writes().flat_map(|write| &write.loads).map(|load| load.range.len()).sum()
+    }
+
+    /// Number of WRITE_FLASH commands
+    pub fn write_count(&self) -> usize {
+        self.writes().count()
+    }
+
+    /// Number of flash pages written
+    pub fn pages_written(&self) -> usize {
+        self.writes().map(|write| write.n_pages as usize).sum()
+    }
+
+    /// Number of READ_BUFFER and READ_FLASH requests, without the reloads of corrupted segments
+    pub fn read_count(&self) -> usize {
+        self.steps
+            .iter()
+            .map(|step| match step {
+                PlanStep::Erase(write) | PlanStep::Write(write) => match self.buffer_verify {
+                    BufferVerify::Off => 0,
+                    BufferVerify::Full => write.loads.len(),
+                    BufferVerify::Sampled(n) => write.loads.len().div_ceil(n.max(1)),
+                },
+                // Reads never cross a page boundary
+                PlanStep::Verify(range) => {
+                    let mut reads = 0;
+                    let mut offset = range.start;
+                    while offset < range.end {
+                        offset = (offset + self.payload_size.flash_read).min(offset - offset % self.page_size + self.page_size).min(range.end);
+                        reads += 1;
+                    }
+                    reads
+                }
+            })
+            .sum()
+    }
+
+    /// Rough duration of the flashing, without retransmissions
+    ///
+    /// A request and its response take two packets, loads and writes one.
+    pub fn estimated_duration(&self) -> Duration {
+        let packets = self.load_count() + self.write_count() + 2 * self.read_count();
+        PACKET_TIME * packets as u32 + PAGE_WRITE_TIME * self.pages_written() as u32
+    }
+}
+
+impl Display for FlashPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Target 0x{:02X}: {} bytes at 0x{:08X} ({} head and {} tail bytes {:?}), {} pages in {} writes, {} loads ({} bytes), {} reads, about {:.1}s",
+            self.target,
+            self.image_length,
+            self.address + self.head as u32,
+            self.head,
+            self.tail,
+            self.partial_pages,
+            self.pages_written(),
+            self.write_count(),
+            self.load_count(),
+            self.bytes_loaded(),
+            self.read_count(),
+            self.estimated_duration().as_secs_f64()
+        )?;
+        for step in &self.steps {
+            match step {
+                PlanStep::Erase(write) => writeln!(f, "  erase {} pages at page {}", write.n_pages, write.flash_page)?,
+                PlanStep::Write(write) if write.loads.is_empty() => writeln!(
+                    f,
+                    "  write {} pages at page {} from buffer page {}, already loaded",
+                    write.n_pages, write.flash_page, write.buffer_page
+                )?,
+                PlanStep::Write(write) => writeln!(
+                    f,
+                    "  write {} pages at page {} from buffer page {}, {} loads of bytes {:?}",
+                    write.n_pages,
+                    write.flash_page,
+                    write.buffer_page,
+                    write.loads.len(),
+                    write.range()
+                )?,
+                PlanStep::Verify(range) => writeln!(f, "  verify bytes {:?}", range)?,
+            }
+        }
+        Ok(())
+    }
+}
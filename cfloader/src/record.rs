@@ -0,0 +1,263 @@
+// Record and replay of bootloader link sessions
+// A recording link wraps any packet link and logs every exchanged packet to a capture
+// file. The capture can later be replayed to the library without any hardware, which
+// allows to reproduce a flashing failure from someone else's desk.
+//
+// Capture format, one exchange per line:
+//   <time since start in us> <packet hex> <ack 0|1> <ack payload hex>
+// Empty packets and payloads are written as '-', lines starting with '#' are comments.
+
+use std::collections::{HashSet, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::codec::Command;
+use crate::error::{Error, Result};
+use crate::link::{Ack, PacketLink};
+
+const CAPTURE_HEADER: &str = "# cfloader capture v1";
+// Null packet used by the bootloader link to poll for responses
+const POLL_PACKET: [u8; 1] = [0xff];
+
+/// One packet exchange of a capture
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exchange {
+    /// Time of the exchange since the start of the capture
+    pub time: Duration,
+    pub packet: Vec<u8>,
+    pub ack: Ack,
+}
+
+fn invalid_capture(message: String) -> Error {
+    Error::Link(message)
+}
+
+fn to_hex(data: &[u8]) -> String {
+    if data.is_empty() {
+        return "-".to_string();
+    }
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Result<Vec<u8>> {
+    if text == "-" {
+        return Ok(Vec::new());
+    }
+    if !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid_capture(format!("Invalid hex string '{}'", text)));
+    }
+    if !text.len().is_multiple_of(2) {
+        return Err(invalid_capture(format!("Odd length hex string '{}'", text)));
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(|e| invalid_capture(format!("Invalid hex string '{}': {}", text, e))))
+        .collect()
+}
+
+/// Read all the exchanges of a capture file
+pub fn read_capture(path: impl AsRef<Path>) -> Result<Vec<Exchange>> {
+    let file = File::open(path)?;
+    let mut exchanges = Vec::new();
+
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 4 {
+            return Err(invalid_capture(format!("Invalid capture line {}: '{}'", n + 1, line)));
+        }
+        let time = fields[0].parse::<u64>()
+            .map_err(|e| invalid_capture(format!("Invalid time on capture line {}: {}", n + 1, e)))?;
+        exchanges.push(Exchange {
+            time: Duration::from_micros(time),
+            packet: from_hex(fields[1])?,
+            ack: Ack { received: fields[2] == "1", payload: from_hex(fields[3])? },
+        });
+    }
+
+    Ok(exchanges)
+}
+
+/// Packet link wrapper recording every exchange to a capture file
+pub struct RecordingLink<P: PacketLink> {
+    link: P,
+    writer: BufWriter<File>,
+    start: Instant,
+}
+
+impl<P: PacketLink> RecordingLink<P> {
+    /// Record the exchanges of `link` to a new capture file at `path`
+    pub fn create(link: P, path: impl AsRef<Path>) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "{}", CAPTURE_HEADER)?;
+        Ok(RecordingLink { link, writer, start: Instant::now() })
+    }
+
+    /// Get the recorded link
+    pub fn inner(&self) -> &P {
+        &self.link
+    }
+
+    /// Get the recorded link mutably
+    pub fn inner_mut(&mut self) -> &mut P {
+        &mut self.link
+    }
+}
+
+impl<P: PacketLink> PacketLink for RecordingLink<P> {
+    async fn send_packet(&mut self, data: &[u8]) -> Result<Ack> {
+        let ack = self.link.send_packet(data).await?;
+
+        writeln!(
+            self.writer,
+            "{} {} {} {}",
+            self.start.elapsed().as_micros(),
+            to_hex(data),
+            ack.received as u8,
+            to_hex(&ack.payload)
+        )?;
+        // Flush every exchange so that the capture is usable even if the process is killed
+        self.writer.flush()?;
+
+        Ok(ack)
+    }
+
+    fn max_packet_size(&self) -> usize {
+        self.link.max_packet_size()
+    }
+}
+
+/// Packet link serving a recorded session
+///
+/// Outgoing packets are checked against the capture and answered as soon as they are sent,
+/// with the recorded acknowledgement and, in the acknowledgements of the following packets,
+/// the response recorded for them. The link timeouts measure the replay, not the recording:
+/// the null polls and the retransmissions of the replay can differ from the capture without
+/// changing the responses. A packet is matched with the next recorded one within
+/// [REPLAY_WINDOW] packets of the last one replayed, and a packet sent again when the recording
+/// did not is acknowledged like a null poll. Any other packet is reported as a divergence.
+pub struct ReplayLink {
+    exchanges: Vec<Exchange>,
+    // Exchanges of the packets other than null polls, and if they have been replayed
+    requests: Vec<(usize, bool)>,
+    // Recorded response of each exchange
+    responses: Vec<Option<Vec<u8>>>,
+    // First request not replayed yet
+    position: usize,
+    // Last request replayed
+    last: usize,
+    // Responses waiting to be sent in acknowledgement payloads
+    queue: VecDeque<Vec<u8>>,
+    // Packets already replayed, a retransmission of one of them is answered like a null poll
+    sent: HashSet<Vec<u8>>,
+    realtime: bool,
+    start: Option<Instant>,
+}
+
+/// Number of recorded packets searched for a packet sent out of order by a [ReplayLink]
+pub const REPLAY_WINDOW: usize = 32;
+
+impl ReplayLink {
+    pub fn new(exchanges: Vec<Exchange>) -> Self {
+        let requests: Vec<(usize, bool)> = (0..exchanges.len()).filter(|&i| exchanges[i].packet != POLL_PACKET).map(|i| (i, false)).collect();
+        let responses = match_responses(&exchanges);
+        ReplayLink { exchanges, requests, responses, position: 0, last: 0, queue: VecDeque::new(), sent: HashSet::new(), realtime: false, start: None }
+    }
+
+    /// Replay the capture file at `path`
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(ReplayLink::new(read_capture(path)?))
+    }
+
+    /// Enable or disable pacing the replay with the recorded timestamps, disabled by default
+    ///
+    /// Paced replay reproduces the timing of the session, a lossy capture can then diverge when
+    /// the replay retransmits packets at other times than the recording.
+    pub fn set_realtime(&mut self, realtime: bool) {
+        self.realtime = realtime;
+    }
+
+    /// Number of recorded packets, null polls excluded, not replayed yet
+    pub fn remaining(&self) -> usize {
+        self.requests.iter().filter(|(_, replayed)| !replayed).count()
+    }
+}
+
+// Find the recorded response of each packet
+//
+// A response starts with the bytes of the command echoed by the bootloader. It answers the
+// last packet of that command sent before it: an earlier identical packet has been sent again
+// because its response was lost.
+fn match_responses(exchanges: &[Exchange]) -> Vec<Option<Vec<u8>>> {
+    let mut responses = vec![None; exchanges.len()];
+    let mut waiting: Vec<(usize, &[u8])> = Vec::new();
+
+    for (index, exchange) in exchanges.iter().enumerate() {
+        let payload = &exchange.ack.payload;
+        if let Some(position) = waiting.iter().rposition(|(_, echo)| payload.starts_with(echo)) {
+            responses[waiting.remove(position).0] = Some(payload.clone());
+        }
+        if let Ok((_, command)) = Command::decode(&exchange.packet) {
+            let echo = &exchange.packet[..command.echo_length().min(exchange.packet.len())];
+            waiting.retain(|(_, other)| *other != echo);
+            waiting.push((index, echo));
+        }
+    }
+
+    responses
+}
+
+impl PacketLink for ReplayLink {
+    async fn send_packet(&mut self, data: &[u8]) -> Result<Ack> {
+        let start = *self.start.get_or_insert_with(Instant::now);
+
+        if data == POLL_PACKET {
+            return Ok(Ack { received: true, payload: self.queue.pop_front().unwrap_or_default() });
+        }
+
+        let end = (self.last + REPLAY_WINDOW).min(self.requests.len());
+        let Some(request) = (self.position..end).find(|&i| !self.requests[i].1 && self.exchanges[self.requests[i].0].packet == data) else {
+            if self.sent.contains(data) {
+                // Sent again by the replay only, like a null poll
+                return Ok(Ack { received: true, payload: self.queue.pop_front().unwrap_or_default() });
+            }
+            let expected = self.requests.get(self.position).map(|&(index, _)| format!("{:02X?}", self.exchanges[index].packet));
+            return Err(Error::Link(format!(
+                "Replay diverged at packet {}: expected {}, got {:02X?}",
+                self.position,
+                expected.unwrap_or_else(|| "none, end of capture reached".to_string()),
+                data
+            )));
+        };
+
+        // The packets left more than a window behind will not be sent anymore
+        self.last = request;
+        self.requests[request].1 = true;
+        for skipped in &mut self.requests[self.position..request.saturating_sub(REPLAY_WINDOW).max(self.position)] {
+            skipped.1 = true;
+        }
+        while self.requests.get(self.position).is_some_and(|(_, replayed)| *replayed) {
+            self.position += 1;
+        }
+
+        let index = self.requests[request].0;
+        if self.realtime {
+            tokio::time::sleep_until((start + self.exchanges[index].time).into()).await;
+        }
+
+        let received = self.exchanges[index].ack.received;
+        // A packet that is not acknowledged does not carry a response
+        let payload = if received { self.queue.pop_front().unwrap_or_default() } else { Vec::new() };
+        if let Some(response) = &self.responses[index] {
+            self.queue.push_back(response.clone());
+        }
+        self.sent.insert(data.to_vec());
+
+        Ok(Ack { received, payload })
+    }
+}
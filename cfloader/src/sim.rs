@@ -0,0 +1,362 @@
+// In-process simulation of the Crazyflie 2.x bootloaders
+// Implements the nRF51 and STM32 bootloader protocol on top of a RAM buffer and flash
+// memory model so that the full flashing stack can be exercised without hardware.
+//
+// The simulator is a packet link: like the real radio, the response to a command is
+// carried by the acknowledgement of the following packet.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::bootloader::{PAYLOAD_HEADER_SIZE, TARGET_NRF51, TARGET_STM32};
+use crate::codec::{Command, Response};
+use crate::error::Result;
+use crate::link::{Ack, MAX_RADIO_PACKET_SIZE, PacketLink};
+use crate::packets::{BufferReadPacket, FlashReadPacket, FlashWriteResponse, InfoPacket};
+
+/// Geometry and timing of one simulated bootloader
+#[derive(Debug, Clone)]
+pub struct SimTargetConfig {
+    pub page_size: u16,
+    pub n_buff_page: u16,
+    pub n_flash_page: u16,
+    pub flash_start: u16,
+    pub version: u8,
+    /// Time needed to erase and program one flash page
+    pub page_write_time: Duration,
+    /// Process commands other than flash writes while a flash write is in progress
+    pub concurrent_write: bool,
+    /// Data bytes stored from one LOAD_BUFFER packet, the end of longer loads is dropped
+    pub load_size: usize,
+    /// Largest data returned by one READ_BUFFER response
+    pub read_size: usize,
+    /// Largest data returned by one READ_FLASH response
+    pub flash_read_size: usize,
+}
+
+impl SimTargetConfig {
+    /// Geometry of the nRF51822 bootloader
+    pub fn nrf51() -> Self {
+        SimTargetConfig {
+            page_size: 1024,
+            n_buff_page: 10,
+            n_flash_page: 232,
+            flash_start: 88,
+            version: 0x10,
+            page_write_time: Duration::ZERO,
+            concurrent_write: false,
+            load_size: MAX_RADIO_PACKET_SIZE - PAYLOAD_HEADER_SIZE,
+            read_size: MAX_RADIO_PACKET_SIZE - PAYLOAD_HEADER_SIZE,
+            flash_read_size: MAX_RADIO_PACKET_SIZE - PAYLOAD_HEADER_SIZE,
+        }
+    }
+
+    /// Geometry of the STM32F405 bootloader
+    pub fn stm32() -> Self {
+        SimTargetConfig {
+            page_size: 1024,
+            n_buff_page: 10,
+            n_flash_page: 1024,
+            flash_start: 16,
+            version: 0x10,
+            page_write_time: Duration::ZERO,
+            concurrent_write: false,
+            load_size: MAX_RADIO_PACKET_SIZE - PAYLOAD_HEADER_SIZE,
+            read_size: MAX_RADIO_PACKET_SIZE - PAYLOAD_HEADER_SIZE,
+            flash_read_size: MAX_RADIO_PACKET_SIZE - PAYLOAD_HEADER_SIZE,
+        }
+    }
+}
+
+// State of one simulated bootloader
+struct SimTarget {
+    config: SimTargetConfig,
+    buffer: Vec<u8>,
+    flash: Vec<u8>,
+    // Result of the last flash write: (done, error)
+    status: (u8, u8),
+    // Flash write in progress, the target does not process commands until it completes unless
+    // configured for concurrent writes
+    write: Option<PendingWrite>,
+    write_count: usize,
+}
+
+struct PendingWrite {
+    end: Instant,
+    buffer_page: u16,
+    flash_page: u16,
+    n_pages: u16,
+}
+
+impl SimTarget {
+    fn new(config: SimTargetConfig) -> Self {
+        let buffer = vec![0xff; config.page_size as usize * config.n_buff_page as usize];
+        let flash = vec![0xff; config.page_size as usize * config.n_flash_page as usize];
+        SimTarget { config, buffer, flash, status: (1, 0), write: None, write_count: 0 }
+    }
+
+    // Complete the pending write if its time has come, returns the write response
+    fn poll_write(&mut self, now: Instant) -> Option<Response> {
+        if self.write.as_ref().is_some_and(|write| now >= write.end) {
+            let write = self.write.take().unwrap();
+            let page_size = self.config.page_size as usize;
+            let src = write.buffer_page as usize * page_size;
+            let dst = write.flash_page as usize * page_size;
+            let len = write.n_pages as usize * page_size;
+            self.flash[dst..dst + len].copy_from_slice(&self.buffer[src..src + len]);
+            self.status = (1, 0);
+            self.write_count += 1;
+            Some(Response::FlashWrite(FlashWriteResponse { done: 1, error: 0 }))
+        } else {
+            None
+        }
+    }
+
+    fn handle(&mut self, target: u8, command: Command, now: Instant) -> Option<Response> {
+        let page_size = self.config.page_size as usize;
+
+        match command {
+            Command::GetInfo => {
+                // A target configured with an invalid geometry does not answer
+                let config = &self.config;
+                InfoPacket::new(config.page_size, config.n_buff_page, config.n_flash_page, config.flash_start, config.version)
+                    .ok()
+                    .map(Response::Info)
+            }
+            Command::GetMapping => {
+                // STM32F405 sectors: 4x16K, 1x64K, 7x128K
+                let mapping = if target == TARGET_STM32 { vec![4, 16, 1, 64, 7, 128] } else { Vec::new() };
+                Some(Response::Mapping(mapping))
+            }
+            Command::LoadBuffer { page, address, mut data } => {
+                data.truncate(self.config.load_size);
+                let start = page as usize * page_size + address as usize;
+                if start + data.len() <= self.buffer.len() {
+                    self.buffer[start..start + data.len()].copy_from_slice(&data);
+                }
+                None
+            }
+            Command::ReadBuffer { page, address } => {
+                let data = read_memory(&self.buffer, page as usize * page_size + address as usize, self.config.read_size);
+                Some(Response::BufferRead(BufferReadPacket { page, address, data }))
+            }
+            Command::WriteFlash { buffer_page, flash_page, n_pages } => {
+                let in_bounds = flash_page >= self.config.flash_start
+                    && flash_page as u32 + n_pages as u32 <= self.config.n_flash_page as u32
+                    && buffer_page as u32 + n_pages as u32 <= self.config.n_buff_page as u32;
+                if !in_bounds {
+                    self.status = (0, 1);
+                    return Some(Response::FlashWrite(FlashWriteResponse { done: 0, error: 1 }));
+                }
+
+                self.status = (0, 0);
+                self.write = Some(PendingWrite {
+                    end: now + self.config.page_write_time * n_pages as u32,
+                    buffer_page,
+                    flash_page,
+                    n_pages,
+                });
+                self.poll_write(now)
+            }
+            Command::FlashStatus => {
+                Some(Response::FlashStatus(FlashWriteResponse { done: self.status.0, error: self.status.1 }))
+            }
+            Command::ReadFlash { page, address } => {
+                let data = read_memory(&self.flash, page as usize * page_size + address as usize, self.config.flash_read_size);
+                Some(Response::FlashRead(FlashReadPacket { page, address, data }))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Data returned by a read command: up to `size` bytes
+fn read_memory(memory: &[u8], start: usize, size: usize) -> Vec<u8> {
+    let start = start.min(memory.len());
+    let len = (memory.len() - start).min(size);
+    memory[start..start + len].to_vec()
+}
+
+struct SimState {
+    nrf51: SimTarget,
+    stm32: SimTarget,
+    // Responses waiting to be sent in acknowledgement payloads
+    responses: VecDeque<Vec<u8>>,
+    address: Option<[u8; 5]>,
+    vbat: f32,
+    powered: bool,
+    reset_count: usize,
+    packet_count: usize,
+}
+
+impl SimState {
+    fn target(&mut self, target: u8) -> Option<&mut SimTarget> {
+        match target {
+            TARGET_NRF51 => Some(&mut self.nrf51),
+            TARGET_STM32 => Some(&mut self.stm32),
+            _ => None,
+        }
+    }
+
+    fn receive(&mut self, data: &[u8]) -> Vec<u8> {
+        let now = Instant::now();
+        self.packet_count += 1;
+
+        // The acknowledgement payload is prepared before the packet is received
+        let payload = self.responses.pop_front().unwrap_or_default();
+
+        for target in [TARGET_NRF51, TARGET_STM32] {
+            if let Some(response) = self.target(target).unwrap().poll_write(now) {
+                self.responses.push_back(response.encode(target));
+            }
+        }
+
+        // Polling packets and corrupted commands are ignored
+        if let Ok((target, command)) = Command::decode(data)
+            && let Some(response) = self.handle(target, command, now)
+        {
+            self.responses.push_back(response.encode(target));
+        }
+
+        payload
+    }
+
+    fn handle(&mut self, target: u8, command: Command, now: Instant) -> Option<Response> {
+        match command {
+            Command::SetAddress(address) => {
+                self.address = Some(address);
+                None
+            }
+            Command::ResetInit => None,
+            Command::Reset => {
+                self.reset_count += 1;
+                None
+            }
+            Command::AllOff | Command::SysOff => {
+                self.powered = false;
+                None
+            }
+            Command::SysOn => {
+                self.powered = true;
+                None
+            }
+            Command::GetVbat => Some(Response::Vbat(self.vbat)),
+            command => {
+                let sim_target = self.target(target)?;
+                // A target busy writing flash does not process commands, or only the ones not
+                // starting another write
+                if sim_target.write.is_some()
+                    && (!sim_target.config.concurrent_write || matches!(command, Command::WriteFlash { .. }))
+                {
+                    return None;
+                }
+                sim_target.handle(target, command, now)
+            }
+        }
+    }
+}
+
+/// Simulated Crazyflie 2.x with both nRF51 and STM32 bootloaders
+///
+/// The simulator can be cloned: all clones share the same device so that a test can keep a
+/// handle to inspect the flash content while a [crate::Bllink] owns the link.
+#[derive(Clone)]
+pub struct SimulatedCrazyflie {
+    state: Arc<Mutex<SimState>>,
+    max_packet_size: usize,
+}
+
+impl SimulatedCrazyflie {
+    pub fn new(nrf51: SimTargetConfig, stm32: SimTargetConfig) -> Self {
+        let state = SimState {
+            nrf51: SimTarget::new(nrf51),
+            stm32: SimTarget::new(stm32),
+            responses: VecDeque::new(),
+            address: None,
+            vbat: 3.7,
+            powered: true,
+            reset_count: 0,
+            packet_count: 0,
+        };
+        SimulatedCrazyflie { state: Arc::new(Mutex::new(state)), max_packet_size: MAX_RADIO_PACKET_SIZE }
+    }
+
+    /// Carry packets of up to `size` bytes instead of the 32 bytes of a Crazyradio
+    ///
+    /// The payloads of the simulated bootloaders are limited by their [SimTargetConfig].
+    pub fn with_max_packet_size(self, size: usize) -> Self {
+        SimulatedCrazyflie { max_packet_size: size, ..self }
+    }
+
+    fn with_target<T>(&self, target: u8, f: impl FnOnce(&mut SimTarget) -> T) -> T {
+        let mut state = self.state.lock().unwrap();
+        let target = state.target(target).expect("Invalid simulated target");
+        f(target)
+    }
+
+    /// Content of the whole flash of a target
+    pub fn flash(&self, target: u8) -> Vec<u8> {
+        self.with_target(target, |t| t.flash.clone())
+    }
+
+    /// Overwrite part of the flash of a target, for example to simulate existing firmware
+    pub fn set_flash(&self, target: u8, address: u32, data: &[u8]) {
+        self.with_target(target, |t| {
+            let address = address as usize;
+            t.flash[address..address + data.len()].copy_from_slice(data);
+        })
+    }
+
+    /// Content of the RAM buffer of a target
+    pub fn buffer(&self, target: u8) -> Vec<u8> {
+        self.with_target(target, |t| t.buffer.clone())
+    }
+
+    /// Number of flash write operations completed by a target
+    pub fn write_count(&self, target: u8) -> usize {
+        self.with_target(target, |t| t.write_count)
+    }
+
+    /// Number of packets received by the simulated radio
+    pub fn packet_count(&self) -> usize {
+        self.state.lock().unwrap().packet_count
+    }
+
+    /// Number of reset commands received
+    pub fn reset_count(&self) -> usize {
+        self.state.lock().unwrap().reset_count
+    }
+
+    /// Current system power state, changed by the power commands
+    pub fn is_powered(&self) -> bool {
+        self.state.lock().unwrap().powered
+    }
+
+    /// Radio address set by the last SET_ADDRESS command
+    pub fn address(&self) -> Option<[u8; 5]> {
+        self.state.lock().unwrap().address
+    }
+
+    /// Set the battery voltage reported by GETVBAT
+    pub fn set_vbat(&self, vbat: f32) {
+        self.state.lock().unwrap().vbat = vbat;
+    }
+}
+
+impl Default for SimulatedCrazyflie {
+    fn default() -> Self {
+        SimulatedCrazyflie::new(SimTargetConfig::nrf51(), SimTargetConfig::stm32())
+    }
+}
+
+impl PacketLink for SimulatedCrazyflie {
+    async fn send_packet(&mut self, data: &[u8]) -> Result<Ack> {
+        let payload = self.state.lock().unwrap().receive(data);
+        Ok(Ack { received: true, payload })
+    }
+
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+}
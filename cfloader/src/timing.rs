@@ -0,0 +1,126 @@
+// Timing model of a bootloader link
+// The round-trip time of a request depends on the radio, on the USB host and on the bootloader
+// (the STM32 is reached through the nRF51), the acknowledgement rate on the interference in the
+// 2.4 GHz band. Both are measured on the exchanges to choose the response timeouts and the delays
+// between packets, instead of fixed values that are too slow on a clean link and too impatient
+// on a busy one.
+
+use std::time::Duration;
+
+use crate::config::RetryPolicy;
+
+// Round-trip time assumed until one has been measured
+const INITIAL_ROUND_TRIP: Duration = Duration::from_micros(500);
+// Shortest response timeout, unless the policy of the command is shorter
+const MIN_RESPONSE_TIMEOUT: Duration = Duration::from_millis(5);
+// Largest backoff factor counted, the policies of the commands limit it further
+const MAX_BACKOFF: u32 = 64;
+// Delay before sending a packet again after a loss, doubled with each consecutive loss
+const RETRY_DELAY: Duration = Duration::from_millis(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_millis(32);
+// Longest delay between polling packets, reached when a response takes long such as a flash write
+const MAX_POLL_DELAY: Duration = Duration::from_millis(4);
+// Weight of each packet in the acknowledgement rate
+const ACK_RATE_WEIGHT: f64 = 1.0 / 16.0;
+// Acknowledgement rate above which polling packets are sent back-to-back
+const CLEAN_ACK_RATE: f64 = 0.9;
+
+/// Round-trip time and acknowledgement rate of a link, learnt from its exchanges
+///
+/// [crate::Bllink] keeps one per target and [crate::config::CommandClass], as an STM32 request
+/// relayed by the nRF51 or a flash write takes longer to answer than an nRF51 info query.
+///
+/// The response timeout is computed from the smoothed round-trip time and its variation as TCP
+/// does (RFC 6298), and doubled after each timeout until a response arrives in time. Polling
+/// packets are sent back-to-back while a response is expected on a link where nearly all packets
+/// are acknowledged, and the delay before sending a lost packet again doubles with each
+/// consecutive loss.
+#[derive(Debug, Clone)]
+pub struct LinkTiming {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    backoff: u32,
+    ack_rate: f64,
+    consecutive_losses: u32,
+}
+
+impl Default for LinkTiming {
+    fn default() -> Self {
+        LinkTiming { srtt: None, rttvar: Duration::ZERO, backoff: 1, ack_rate: 1.0, consecutive_losses: 0 }
+    }
+}
+
+impl LinkTiming {
+    /// Record if a packet has been acknowledged
+    pub fn record_ack(&mut self, received: bool) {
+        let sample = if received { 1.0 } else { 0.0 };
+        self.ack_rate += ACK_RATE_WEIGHT * (sample - self.ack_rate);
+        self.consecutive_losses = if received { 0 } else { self.consecutive_losses.saturating_add(1) };
+    }
+
+    /// Record the time between sending a request and receiving its response
+    ///
+    /// Only requests answered without being sent again are measured, the response of a request
+    /// sent again cannot be matched to one of its copies.
+    pub fn record_round_trip(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        self.backoff = 1;
+    }
+
+    /// Record a request that has not been answered in time
+    pub fn record_timeout(&mut self) {
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+
+    /// Time to wait for the response to a request before sending it again
+    ///
+    /// The timeout of the `policy` is used until a round trip has been measured, and is never
+    /// exceeded before the backoff.
+    pub fn response_timeout(&self, policy: &RetryPolicy) -> Duration {
+        let timeout = match self.srtt {
+            Some(srtt) => (srtt + self.rttvar * 4).min(policy.timeout),
+            None => policy.timeout,
+        };
+        (timeout * self.backoff.min(policy.backoff.max(1))).max(MIN_RESPONSE_TIMEOUT.min(policy.timeout))
+    }
+
+    /// Delay before sending again a packet that has not been acknowledged
+    pub fn retry_delay(&self) -> Duration {
+        let doublings = self.consecutive_losses.saturating_sub(3).min(16);
+        (RETRY_DELAY * 2u32.pow(doublings)).min(MAX_RETRY_DELAY)
+    }
+
+    /// Delay before the next polling packet after waiting `waited` for a response
+    ///
+    /// The responses arriving within the round-trip time are polled back-to-back on a clean
+    /// link, longer waits such as flash writes are polled less and less often.
+    pub fn poll_delay(&self, waited: Duration) -> Duration {
+        let expected = self.srtt.unwrap_or(INITIAL_ROUND_TRIP) * 2;
+        if self.consecutive_losses > 0 {
+            self.retry_delay()
+        } else if waited <= expected && self.ack_rate >= CLEAN_ACK_RATE {
+            Duration::ZERO
+        } else {
+            (waited / 4).clamp(RETRY_DELAY, MAX_POLL_DELAY)
+        }
+    }
+
+    /// Smoothed round-trip time of the requests, None until one has been measured
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Recent rate of acknowledged packets, from 0.0 to 1.0
+    pub fn ack_rate(&self) -> f64 {
+        self.ack_rate
+    }
+}
@@ -0,0 +1,164 @@
+// Flash verification report
+// The flash is read back and compared to the image, all the differences are collected
+// instead of stopping at the first one so that a tool can show what went wrong.
+
+use std::fmt::Display;
+use std::time::Duration;
+
+/// How much of the image is read back by [crate::CFLoader::verify_with_mode]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum VerifyMode {
+    /// Every byte of the image is read back
+    #[default]
+    Full,
+    /// One read packet out of `n` is read back, quick check that the pages have been written
+    Sampled(usize),
+}
+
+/// Range of consecutive flash bytes that differ from the image, it never spans two pages
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub page: u16,
+    /// Offset of the first byte of the range in the page
+    pub offset: u16,
+    /// Flash address of the first byte of the range
+    pub address: u32,
+    /// Image content
+    pub expected: Vec<u8>,
+    /// Flash content
+    pub actual: Vec<u8>,
+}
+
+impl Mismatch {
+    pub fn len(&self) -> usize {
+        self.expected.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.expected.is_empty()
+    }
+}
+
+/// Result of a flash verification
+#[derive(Debug, Clone)]
+pub struct VerifyReport {
+    pub target: u8,
+    /// Flash address of the image
+    pub address: u32,
+    /// Size of the image
+    pub length: usize,
+    /// Number of bytes read back and compared
+    pub bytes_checked: usize,
+    pub mismatches: Vec<Mismatch>,
+    /// Pages flashed again because they did not match, by [crate::CFLoader::flash_and_verify]
+    pub repaired_pages: Vec<u16>,
+    pub duration: Duration,
+}
+
+impl VerifyReport {
+    pub(crate) fn new(target: u8, address: u32, length: usize) -> Self {
+        VerifyReport {
+            target,
+            address,
+            length,
+            bytes_checked: 0,
+            mismatches: Vec::new(),
+            repaired_pages: Vec::new(),
+            duration: Duration::ZERO,
+        }
+    }
+
+    /// True if all the bytes checked match the image
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+
+    /// True if every byte of the image has been checked
+    pub fn is_complete(&self) -> bool {
+        self.bytes_checked == self.length
+    }
+
+    /// Number of flash bytes that differ from the image
+    pub fn mismatched_bytes(&self) -> usize {
+        self.mismatches.iter().map(Mismatch::len).sum()
+    }
+
+    /// Pages containing at least one mismatch, in increasing order
+    pub fn mismatched_pages(&self) -> Vec<u16> {
+        let mut pages: Vec<u16> = self.mismatches.iter().map(|mismatch| mismatch.page).collect();
+        pages.dedup();
+        pages
+    }
+
+    /// Read-back throughput in bytes per second
+    pub fn throughput(&self) -> f64 {
+        if self.duration.is_zero() {
+            return 0.0;
+        }
+        self.bytes_checked as f64 / self.duration.as_secs_f64()
+    }
+
+    // Compare the flash content read at `address`, in `page`, with the expected image bytes
+    //
+    // Flash bytes missing from a short read are reported as mismatches. A mismatch continuing
+    // the last one of the report is merged with it.
+    pub(crate) fn compare(&mut self, page: u16, offset: u16, address: u32, expected: &[u8], actual: &[u8]) {
+        self.bytes_checked += expected.len();
+
+        let mut i = 0;
+        while i < expected.len() {
+            if actual.get(i) == Some(&expected[i]) {
+                i += 1;
+                continue;
+            }
+
+            let start = i;
+            while i < expected.len() && actual.get(i) != Some(&expected[i]) {
+                i += 1;
+            }
+            let actual_range = &actual[start.min(actual.len())..i.min(actual.len())];
+            let range_address = address + start as u32;
+
+            match self.mismatches.last_mut() {
+                Some(last) if last.page == page && last.address + last.len() as u32 == range_address => {
+                    last.expected.extend_from_slice(&expected[start..i]);
+                    last.actual.extend_from_slice(actual_range);
+                }
+                _ => self.mismatches.push(Mismatch {
+                    page,
+                    offset: offset + start as u16,
+                    address: range_address,
+                    expected: expected[start..i].to_vec(),
+                    actual: actual_range.to_vec(),
+                }),
+            }
+        }
+    }
+}
+
+impl Display for VerifyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Target 0x{:02X}: {}/{} bytes checked at 0x{:08X} in {:.2}s ({:.0} B/s), {} mismatched bytes",
+            self.target,
+            self.bytes_checked,
+            self.length,
+            self.address,
+            self.duration.as_secs_f64(),
+            self.throughput(),
+            self.mismatched_bytes()
+        )?;
+        if !self.repaired_pages.is_empty() {
+            writeln!(f, "  repaired pages: {:?}", self.repaired_pages)?;
+        }
+        for mismatch in &self.mismatches {
+            writeln!(
+                f,
+                "  page {} offset {} (0x{:08X}): expected {:02X?}, flash {:02X?}",
+                mismatch.page, mismatch.offset, mismatch.address, mismatch.expected, mismatch.actual
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+// Checkpoint files, saved and loaded without any link
+
+use cfloader::checkpoint::Checkpoint;
+use cfloader::packets::InfoPacket;
+use cfloader::{bootloader, Error};
+
+const TARGET: u8 = bootloader::TARGET_STM32;
+const START_ADDRESS: u32 = 16 * 1024;
+
+fn stm32_info() -> InfoPacket {
+    InfoPacket::new(1024, 10, 1024, 16, 0x10).unwrap()
+}
+
+// STM32 geometry with a CPU id, as reported by GET_INFO
+fn stm32_info_with_cpu_id(cpu_id: u8) -> InfoPacket {
+    let mut bytes = vec![0x10, 0x00, 0x04, 0x0a, 0x00, 0x00, 0x04, 0x10, 0x00];
+    bytes.extend([cpu_id; 12]);
+    bytes.push(0x10);
+    InfoPacket::from_bytes(&bytes).unwrap()
+}
+
+fn is_checkpoint_error(result: cfloader::Result<()>) -> bool {
+    matches!(result, Err(Error::Checkpoint(_)))
+}
+
+#[test]
+fn checkpoint_save_and_load() {
+    let path = std::env::temp_dir().join(format!("cfloader-checkpoint-{}", std::process::id()));
+    let image: Vec<u8> = (0..5000).map(|i| (i * 3) as u8).collect();
+    let checkpoint = Checkpoint::new(TARGET, START_ADDRESS, &image, &stm32_info(), 19);
+
+    checkpoint.save(&path).unwrap();
+    let loaded = Checkpoint::load(&path).unwrap().unwrap();
+    assert_eq!(loaded, checkpoint);
+    assert_eq!(loaded.committed_length(), 4 * 1024);
+    loaded.validate(TARGET, START_ADDRESS, &image, &stm32_info()).unwrap();
+
+    Checkpoint::remove(&path).unwrap();
+    assert!(Checkpoint::load(&path).unwrap().is_none());
+    Checkpoint::remove(&path).unwrap();
+}
+
+#[test]
+fn checkpoint_rejects_another_flashing() {
+    let image: Vec<u8> = (0..5000).map(|i| (i * 3) as u8).collect();
+    let info = stm32_info();
+    let checkpoint = Checkpoint::new(TARGET, START_ADDRESS, &image, &info, 19);
+
+    let mut other_image = image.clone();
+    other_image[4000] ^= 1;
+    assert!(is_checkpoint_error(checkpoint.validate(TARGET, START_ADDRESS, &other_image, &info)));
+    assert!(is_checkpoint_error(checkpoint.validate(TARGET, START_ADDRESS, &image[..4999], &info)));
+
+    let other_pages = InfoPacket::new(2048, 10, 512, 8, 0x10).unwrap();
+    assert!(is_checkpoint_error(checkpoint.validate(TARGET, START_ADDRESS, &image, &other_pages)));
+    let other_flash = InfoPacket::new(1024, 10, 512, 16, 0x10).unwrap();
+    assert!(is_checkpoint_error(checkpoint.validate(TARGET, START_ADDRESS, &image, &other_flash)));
+    let other_start = InfoPacket::new(1024, 10, 1024, 12, 0x10).unwrap();
+    assert!(is_checkpoint_error(checkpoint.validate(TARGET, START_ADDRESS, &image, &other_start)));
+
+    assert!(is_checkpoint_error(checkpoint.validate(bootloader::TARGET_NRF51, START_ADDRESS, &image, &info)));
+    assert!(is_checkpoint_error(checkpoint.validate(TARGET, START_ADDRESS + 1024, &image, &info)));
+}
+
+#[test]
+fn checkpoint_recognizes_the_cpu_id() {
+    let path = std::env::temp_dir().join(format!("cfloader-checkpoint-cpu-{}", std::process::id()));
+    let image: Vec<u8> = (0..5000).map(|i| (i * 5) as u8).collect();
+    let info = stm32_info_with_cpu_id(0x42);
+    let checkpoint = Checkpoint::new(TARGET, START_ADDRESS, &image, &info, 17);
+    assert_eq!(checkpoint.cpu_id, Some([0x42; 12]));
+
+    checkpoint.save(&path).unwrap();
+    let loaded = Checkpoint::load(&path).unwrap().unwrap();
+    assert_eq!(loaded, checkpoint);
+    loaded.validate(TARGET, START_ADDRESS, &image, &info).unwrap();
+    Checkpoint::remove(&path).unwrap();
+
+    // Another device, the CPU id is only compared when the bootloader reports one
+    assert!(is_checkpoint_error(checkpoint.validate(TARGET, START_ADDRESS, &image, &stm32_info_with_cpu_id(0x43))));
+    checkpoint.validate(TARGET, START_ADDRESS, &image, &stm32_info()).unwrap();
+}
+
+#[test]
+fn checkpoint_rejects_invalid_files() {
+    let path = std::env::temp_dir().join(format!("cfloader-checkpoint-invalid-{}", std::process::id()));
+    let image: Vec<u8> = (0..5000).map(|i| (i * 3) as u8).collect();
+    Checkpoint::new(TARGET, START_ADDRESS, &image, &stm32_info(), 19).save(&path).unwrap();
+    let text = std::fs::read_to_string(&path).unwrap();
+
+    let invalid = [
+        // No header
+        text.lines().skip(1).map(|line| format!("{}\n", line)).collect::<String>(),
+        // Values not fitting their field
+        text.replace("target ff", "target 1ff"),
+        text.replace("last_page 0013", "last_page 10013"),
+        text.replace("address 00004000", "address 100004000"),
+        // Missing value
+        text.replace("hash", "hush"),
+    ];
+    for content in invalid {
+        std::fs::write(&path, &content).unwrap();
+        assert!(matches!(Checkpoint::load(&path), Err(Error::Checkpoint(_))), "{}", content);
+    }
+    Checkpoint::remove(&path).unwrap();
+}
@@ -0,0 +1,109 @@
+// Decoding of bootloader responses, complete, cut short and corrupted
+
+use cfloader::codec::{Command, Response};
+use cfloader::packets::{BufferReadPacket, FlashError, FlashReadPacket, FlashWriteResponse, InfoPacket};
+use cfloader::{bootloader, Error};
+
+const TARGET: u8 = bootloader::TARGET_STM32;
+
+// One response of each type with the shortest length it decodes from
+fn responses() -> Vec<(Response, usize)> {
+    vec![
+        (Response::Info(InfoPacket::new(1024, 10, 1024, 16, 0x10).unwrap()), 24),
+        (Response::Mapping(vec![16, 4, 64, 1]), 3),
+        (Response::BufferRead(BufferReadPacket { page: 3, address: 100, data: vec![1, 2, 3, 4] }), 7),
+        (Response::FlashWrite(FlashWriteResponse { done: 1, error: 0 }), 5),
+        (Response::FlashStatus(FlashWriteResponse { done: 0, error: 0 }), 5),
+        (Response::FlashRead(FlashReadPacket { page: 20, address: 8, data: vec![5, 6, 7] }), 7),
+        (Response::Vbat(3.7), 7),
+    ]
+}
+
+fn is_malformed<T>(result: cfloader::Result<T>) -> bool {
+    matches!(result, Err(Error::MalformedPacket(_)))
+}
+
+#[test]
+fn responses_decode_as_encoded() {
+    for (response, _) in responses() {
+        let packet = response.encode(TARGET);
+        let decoded = Response::decode(TARGET, &packet).unwrap();
+        assert_eq!(decoded.encode(TARGET), packet);
+    }
+}
+
+#[test]
+fn short_responses_are_malformed() {
+    for (response, min_length) in responses() {
+        let packet = response.encode(TARGET);
+        for length in 0..min_length {
+            assert!(is_malformed(Response::decode(TARGET, &packet[..length])), "{:02X?}", &packet[..length]);
+        }
+        assert!(Response::decode(TARGET, &packet[..min_length]).is_ok(), "{:02X?}", &packet[..min_length]);
+    }
+}
+
+#[test]
+fn truncated_read_responses_keep_their_echo() {
+    // The data of a read cut after its echo is shorter, the caller finds it out from the length
+    let packet = Response::FlashRead(FlashReadPacket { page: 20, address: 8, data: vec![5, 6, 7] }).encode(TARGET);
+    match Response::decode(TARGET, &packet[..8]).unwrap() {
+        Response::FlashRead(read) => assert_eq!((read.page, read.address, read.data), (20, 8, vec![5])),
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn corrupted_responses_are_malformed() {
+    for (response, _) in responses() {
+        let packet = response.encode(TARGET);
+
+        let mut header = packet.clone();
+        header[0] = 0xfe;
+        assert!(is_malformed(Response::decode(TARGET, &header)), "{:02X?}", header);
+
+        assert!(is_malformed(Response::decode(bootloader::TARGET_NRF51, &packet)), "{:02X?}", packet);
+
+        let mut command = packet.clone();
+        command[2] = 0x42;
+        assert!(is_malformed(Response::decode(TARGET, &command)), "{:02X?}", command);
+    }
+
+    // A geometry that cannot be planned
+    let mut info = Response::Info(InfoPacket::new(1024, 10, 1024, 16, 0x10).unwrap()).encode(TARGET);
+    info[3..5].copy_from_slice(&[0, 0]);
+    assert!(is_malformed(Response::decode(TARGET, &info)));
+}
+
+#[test]
+fn commands_decode_as_encoded() {
+    let commands = [
+        Command::GetInfo,
+        Command::LoadBuffer { page: 2, address: 50, data: vec![9; 25] },
+        Command::ReadBuffer { page: 2, address: 50 },
+        Command::WriteFlash { buffer_page: 0, flash_page: 16, n_pages: 10 },
+        Command::ReadFlash { page: 16, address: 0 },
+    ];
+    for command in commands {
+        let packet = command.encode(TARGET);
+        assert_eq!(Command::decode(&packet).unwrap(), (TARGET, command.clone()));
+        if packet.len() > 3 {
+            assert!(is_malformed(Command::decode(&packet[..packet.len().min(7) - 1])), "{:?}", command);
+        }
+    }
+}
+
+#[test]
+fn unknown_flash_error_is_not_a_success() {
+    let packet = Response::FlashWrite(FlashWriteResponse { done: 1, error: 7 }).encode(TARGET);
+    let Response::FlashWrite(response) = Response::decode(TARGET, &packet).unwrap() else {
+        panic!("not a write response");
+    };
+
+    assert!(response.is_done());
+    assert_eq!(response.error(), FlashError::Unknown(7));
+    assert!(!response.is_success());
+    assert_eq!(u8::from(response.error()), 7);
+    assert_eq!(response.error().to_string(), "Unknown flash error code 7");
+    assert_eq!(FlashError::from(3), FlashError::FlashProgrammingFailed);
+}
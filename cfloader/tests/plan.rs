@@ -0,0 +1,114 @@
+// Flashing plans, computed from the bootloader geometry without any link
+
+use cfloader::packets::InfoPacket;
+use cfloader::plan::PlanStep;
+use cfloader::{bootloader, Error, FlashOptions, FlashPlan, PayloadSize};
+
+const PAGE_SIZE: usize = 1024;
+
+// STM32F405 bootloader geometry: 10 buffer pages, firmware from page 16
+fn stm32_info() -> InfoPacket {
+    InfoPacket::new(PAGE_SIZE as u16, 10, 1024, 16, 0x10).unwrap()
+}
+
+fn image(length: usize) -> Vec<u8> {
+    (0..length).map(|i| (i * 7 + i / PAGE_SIZE) as u8).collect()
+}
+
+#[test]
+fn plan_chunks_image_in_buffer_writes_and_page_loads() {
+    let image = image(12 * PAGE_SIZE + 100);
+    let start_address = 16 * PAGE_SIZE as u32 + 5;
+    let plan = FlashPlan::new(bootloader::TARGET_STM32, start_address, &image, &stm32_info(), PayloadSize::default(), &FlashOptions::default()).unwrap();
+
+    assert_eq!(plan.address, 16 * PAGE_SIZE as u32);
+    assert_eq!(plan.head, 5);
+    assert_eq!(plan.aligned_length(), 13 * PAGE_SIZE);
+
+    // A full buffer of 10 pages, then the 3 remaining pages
+    let writes: Vec<(u16, u16, u16)> = plan.writes().map(|write| (write.buffer_page, write.flash_page, write.n_pages)).collect();
+    assert_eq!(writes, vec![(0, 16, 10), (0, 26, 3)]);
+    assert_eq!(plan.pages_written(), 13);
+
+    // Loads of 25 bytes cover the aligned image in order and never cross a page
+    assert_eq!(plan.load_count(), 13 * PAGE_SIZE.div_ceil(25));
+    assert_eq!(plan.bytes_loaded(), plan.aligned_length());
+    let mut offset = 0;
+    for write in plan.writes() {
+        for load in &write.loads {
+            assert_eq!(load.range.start, offset);
+            assert!(load.range.len() <= 25);
+            assert_eq!(load.address as usize, offset % PAGE_SIZE);
+            assert!(load.address as usize + load.range.len() <= PAGE_SIZE);
+            assert_eq!(load.buffer_page, write.buffer_page + ((offset - write.range().start) / PAGE_SIZE) as u16);
+            offset = load.range.end;
+        }
+    }
+    assert_eq!(offset, plan.aligned_length());
+}
+
+#[test]
+fn plan_alternates_buffer_halves_and_defers_first_page() {
+    let image = image(12 * PAGE_SIZE);
+    let options = FlashOptions { double_buffer: true, safe_order: true, ..Default::default() };
+    let plan = FlashPlan::new(bootloader::TARGET_STM32, 16 * PAGE_SIZE as u32, &image, &stm32_info(), PayloadSize::default(), &options).unwrap();
+
+    // The first page is erased, the rest is written 5 pages at a time from alternating halves of
+    // the buffer and verified, then the first page is written
+    let steps: Vec<String> = plan
+        .steps
+        .iter()
+        .map(|step| match step {
+            PlanStep::Erase(write) => format!("erase {} from {}", write.flash_page, write.buffer_page),
+            PlanStep::Write(write) => format!("write {}+{} from {}", write.flash_page, write.n_pages, write.buffer_page),
+            PlanStep::Verify(range) => format!("verify {:?}", range),
+        })
+        .collect();
+    assert_eq!(
+        steps,
+        vec![
+            "erase 16 from 0",
+            "write 17+5 from 5",
+            "write 22+5 from 0",
+            "write 27+1 from 5",
+            "verify 1024..12288",
+            "write 16+1 from 0",
+        ]
+    );
+}
+
+#[test]
+fn plan_rejects_images_outside_the_flash() {
+    let info = stm32_info();
+    let options = FlashOptions::default();
+
+    let below = FlashPlan::new(bootloader::TARGET_STM32, 15 * PAGE_SIZE as u32, &image(PAGE_SIZE), &info, PayloadSize::default(), &options);
+    assert!(matches!(below, Err(Error::OutOfBounds { .. })));
+
+    let beyond = FlashPlan::new(bootloader::TARGET_STM32, 1020 * PAGE_SIZE as u32, &image(5 * PAGE_SIZE), &info, PayloadSize::default(), &options);
+    assert!(matches!(beyond, Err(Error::OutOfBounds { .. })));
+
+    let empty = FlashPlan::new(bootloader::TARGET_STM32, 16 * PAGE_SIZE as u32, &[], &info, PayloadSize::default(), &options);
+    assert!(matches!(empty, Err(Error::EmptyImage)));
+}
+
+#[test]
+fn dedupe_loads_identical_pages_once() {
+    // Erased pages alternating with data pages
+    let image: Vec<u8> = (0..12 * PAGE_SIZE).map(|i| if (i / PAGE_SIZE).is_multiple_of(2) { 0xff } else { (i * 7 + i / PAGE_SIZE) as u8 }).collect();
+    let start_address = 16 * PAGE_SIZE as u32;
+    let plan = FlashPlan::new(bootloader::TARGET_STM32, start_address, &image, &stm32_info(), PayloadSize::default(), &FlashOptions::default()).unwrap();
+    let mut deduped = plan.clone();
+    deduped.dedupe_pages(&image);
+
+    // The 6 erased pages are loaded once, each data page still needs its loads
+    let page_loads = PAGE_SIZE.div_ceil(25);
+    assert_eq!(plan.load_count(), 12 * page_loads);
+    assert_eq!(deduped.load_count(), 7 * page_loads);
+    assert!(deduped.write_count() > plan.write_count());
+
+    // Every flash page of the image is still written once, from a buffer page holding its content
+    let mut pages: Vec<u16> = deduped.writes().flat_map(|write| write.flash_page..write.flash_page + write.n_pages).collect();
+    pages.sort();
+    assert_eq!(pages, (16..28).collect::<Vec<u16>>());
+}
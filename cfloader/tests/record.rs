@@ -0,0 +1,48 @@
+// Recording a flashing session and replaying it without the device
+
+use std::path::PathBuf;
+
+use cfloader::fault::{FaultConfig, FaultyLink};
+use cfloader::record::{read_capture, RecordingLink, ReplayLink};
+use cfloader::sim::SimulatedCrazyflie;
+use cfloader::{bootloader, Bllink, CFLoader, Error};
+
+const TARGET: u8 = bootloader::TARGET_STM32;
+const START_ADDRESS: u32 = 16 * 1024;
+
+fn capture_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("cfloader-{}-{}.capture", name, std::process::id()))
+}
+
+#[tokio::test]
+async fn lossy_session_replays() {
+    let image: Vec<u8> = (0..3000).map(|i| (i * 13) as u8).collect();
+
+    for seed in 0..4 {
+        let path = capture_path(&format!("lossy-{}", seed));
+        let sim = SimulatedCrazyflie::default();
+        let link = RecordingLink::create(FaultyLink::new(sim.clone(), FaultConfig::packet_loss(seed, 0.2)), &path).unwrap();
+        let mut cfloader = CFLoader::new(Bllink::with_packet_link(link)).await.unwrap();
+        cfloader.flash_image(TARGET, START_ADDRESS, &image).await.unwrap();
+        let recorded = cfloader.read_flash(TARGET, START_ADDRESS, image.len() as u32).await.unwrap();
+        drop(cfloader);
+        assert_eq!(recorded, image, "seed {}", seed);
+
+        let mut cfloader = CFLoader::new(Bllink::with_packet_link(ReplayLink::open(&path).unwrap())).await.unwrap();
+        cfloader.flash_image(TARGET, START_ADDRESS, &image).await.unwrap_or_else(|e| panic!("seed {}: {}", seed, e));
+        let replayed = cfloader.read_flash(TARGET, START_ADDRESS, image.len() as u32).await.unwrap();
+        assert_eq!(replayed, recorded, "seed {}", seed);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+#[test]
+fn capture_with_invalid_hex_is_an_error() {
+    let path = capture_path("invalid");
+    for line in ["0 ffé0 1 -", "0 ff 1 0g", "0 fff 1 -"] {
+        std::fs::write(&path, format!("# cfloader capture v1\n{}\n", line)).unwrap();
+        assert!(matches!(read_capture(&path), Err(Error::Link(_))), "{}", line);
+    }
+    std::fs::remove_file(&path).unwrap();
+}
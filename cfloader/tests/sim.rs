@@ -0,0 +1,965 @@
+// Flashing through the simulated bootloaders, over a perfect and a lossy link
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use cfloader::checkpoint::Checkpoint;
+use cfloader::codec::{CMD_GET_INFO, CMD_LOAD_BUFFER, CMD_READ_FLASH, CMD_WRITE_FLASH};
+use cfloader::config::CommandClass;
+use cfloader::fault::{FaultConfig, FaultyLink};
+use cfloader::link::Ack;
+use cfloader::packets::FlashError;
+use cfloader::sim::{SimTargetConfig, SimulatedCrazyflie};
+use cfloader::verify::Mismatch;
+use cfloader::{bootloader, Bllink, Bootloader, BufferVerify, CFLoader, Error, FlashOptions, LinkConfig, PacketLink, PartialPage, PayloadSize, RetryPolicy};
+
+const TARGET: u8 = bootloader::TARGET_STM32;
+const START_ADDRESS: u32 = 16 * 1024;
+
+fn image(length: usize, seed: u8) -> Vec<u8> {
+    (0..length).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+}
+
+async fn connect<P: PacketLink>(link: P) -> CFLoader<Bllink<P>> {
+    CFLoader::new(Bllink::with_packet_link(link)).await.unwrap()
+}
+
+// Simulator cutting the end of one response to a command
+struct CutResponse {
+    sim: SimulatedCrazyflie,
+    command: u8,
+    // Number of responses to the command before the one that is cut
+    skip: usize,
+    cut: usize,
+}
+
+impl PacketLink for CutResponse {
+    async fn send_packet(&mut self, data: &[u8]) -> cfloader::Result<Ack> {
+        let mut ack = self.sim.send_packet(data).await?;
+        if ack.payload.get(2) == Some(&self.command) {
+            if self.skip == 0 {
+                ack.payload.truncate(ack.payload.len().saturating_sub(self.cut));
+            }
+            self.skip = self.skip.wrapping_sub(1);
+        }
+        Ok(ack)
+    }
+}
+
+// Simulator reporting an out of bounds error in one write response
+struct FailWrite {
+    sim: SimulatedCrazyflie,
+    // Number of write responses before the failed one
+    skip: usize,
+}
+
+impl PacketLink for FailWrite {
+    async fn send_packet(&mut self, data: &[u8]) -> cfloader::Result<Ack> {
+        let mut ack = self.sim.send_packet(data).await?;
+        if ack.payload.get(2) == Some(&CMD_WRITE_FLASH) && ack.payload.len() >= 5 {
+            if self.skip == 0 {
+                ack.payload[4] = FlashError::AddressOutOfBounds.into();
+            }
+            self.skip = self.skip.wrapping_sub(1);
+        }
+        Ok(ack)
+    }
+}
+
+// Packet link counting the LOAD_BUFFER packets sent to the wrapped link
+struct CountLoads<P: PacketLink> {
+    link: P,
+    loads: usize,
+}
+
+impl<P: PacketLink> PacketLink for CountLoads<P> {
+    async fn send_packet(&mut self, data: &[u8]) -> cfloader::Result<Ack> {
+        if data.get(2) == Some(&CMD_LOAD_BUFFER) {
+            self.loads += 1;
+        }
+        self.link.send_packet(data).await
+    }
+}
+
+// Simulator losing the response to every other request of a command and cutting the end of
+// the others, counting the requests
+struct LoseOrCutResponses {
+    sim: SimulatedCrazyflie,
+    command: u8,
+    cut: usize,
+    requests: usize,
+}
+
+impl PacketLink for LoseOrCutResponses {
+    async fn send_packet(&mut self, data: &[u8]) -> cfloader::Result<Ack> {
+        if data.get(2) == Some(&self.command) {
+            self.requests += 1;
+        }
+        let mut ack = self.sim.send_packet(data).await?;
+        if ack.payload.get(2) == Some(&self.command) {
+            if self.requests % 2 == 1 {
+                ack.payload.clear();
+            } else {
+                ack.payload.truncate(ack.payload.len().saturating_sub(self.cut));
+            }
+        }
+        Ok(ack)
+    }
+}
+
+// Simulator losing the first `times` packets of each request of a command
+struct LoseRequests {
+    sim: SimulatedCrazyflie,
+    command: u8,
+    times: usize,
+    lost: HashMap<Vec<u8>, usize>,
+}
+
+impl PacketLink for LoseRequests {
+    async fn send_packet(&mut self, data: &[u8]) -> cfloader::Result<Ack> {
+        if data.get(2) == Some(&self.command) {
+            let lost = self.lost.entry(data.to_vec()).or_default();
+            if *lost < self.times {
+                *lost += 1;
+                return Ok(Ack { received: false, payload: Vec::new() });
+            }
+        }
+        self.sim.send_packet(data).await
+    }
+}
+
+// Simulator clearing a flash byte once the first flash write is complete, only once or after
+// every packet when the byte is `stuck`
+struct DamageFlash {
+    sim: SimulatedCrazyflie,
+    address: u32,
+    stuck: bool,
+    damaged: bool,
+}
+
+impl PacketLink for DamageFlash {
+    async fn send_packet(&mut self, data: &[u8]) -> cfloader::Result<Ack> {
+        let ack = self.sim.send_packet(data).await?;
+        if self.sim.write_count(TARGET) > 0 && (self.stuck || !self.damaged) {
+            self.sim.set_flash(TARGET, self.address, &[0x00]);
+            self.damaged = true;
+        }
+        Ok(ack)
+    }
+}
+
+// Simulator counting the LOAD_BUFFER packets sent while a flash write is in progress
+struct OverlapLoads {
+    sim: SimulatedCrazyflie,
+    // Writes completed when the last write command has been sent, while it is in progress
+    writing: Option<usize>,
+    overlapped: usize,
+}
+
+impl PacketLink for OverlapLoads {
+    async fn send_packet(&mut self, data: &[u8]) -> cfloader::Result<Ack> {
+        if self.writing.is_some_and(|writes| self.sim.write_count(TARGET) > writes) {
+            self.writing = None;
+        }
+        match data.get(2) {
+            Some(&CMD_WRITE_FLASH) => self.writing = Some(self.sim.write_count(TARGET)),
+            Some(&CMD_LOAD_BUFFER) if self.writing.is_some() => self.overlapped += 1,
+            _ => {}
+        }
+        self.sim.send_packet(data).await
+    }
+}
+
+// Simulator losing the acknowledgements and responses of the write commands, counting the
+// GET_INFO packets. The flash byte at `stuck`, if any, is cleared after every packet.
+struct LoseWriteAcks {
+    sim: SimulatedCrazyflie,
+    stuck: Option<u32>,
+    infos: usize,
+}
+
+impl PacketLink for LoseWriteAcks {
+    async fn send_packet(&mut self, data: &[u8]) -> cfloader::Result<Ack> {
+        if data.get(2) == Some(&CMD_GET_INFO) {
+            self.infos += 1;
+        }
+        let mut ack = self.sim.send_packet(data).await?;
+        if let Some(address) = self.stuck {
+            self.sim.set_flash(TARGET, address, &[0x00]);
+        }
+        if data.get(2) == Some(&CMD_WRITE_FLASH) {
+            ack.received = false;
+        }
+        if data.get(2) == Some(&CMD_WRITE_FLASH) || ack.payload.get(2) == Some(&CMD_WRITE_FLASH) {
+            ack.payload.clear();
+        }
+        Ok(ack)
+    }
+}
+
+// Simulator that stops answering once a number of flash writes are complete, like a radio
+// unplugged during flashing
+struct Unplug {
+    sim: SimulatedCrazyflie,
+    writes: usize,
+}
+
+impl PacketLink for Unplug {
+    async fn send_packet(&mut self, data: &[u8]) -> cfloader::Result<Ack> {
+        if self.sim.write_count(TARGET) >= self.writes {
+            return Ok(Ack { received: false, payload: Vec::new() });
+        }
+        self.sim.send_packet(data).await
+    }
+}
+
+// Simulator keeping the checkpoint saved after the last flash write, as left by a process killed
+// before removing it. Its temporary file is linked to `kept` once the other writes are complete.
+struct KeepLastCheckpoint {
+    sim: SimulatedCrazyflie,
+    checkpoint: PathBuf,
+    kept: PathBuf,
+    writes: usize,
+}
+
+impl PacketLink for KeepLastCheckpoint {
+    async fn send_packet(&mut self, data: &[u8]) -> cfloader::Result<Ack> {
+        if data.get(2) == Some(&CMD_LOAD_BUFFER) && self.sim.write_count(TARGET) == self.writes - 1 && !self.kept.exists() {
+            let mut temporary = self.checkpoint.as_os_str().to_owned();
+            temporary.push(".tmp");
+            fs::write(&self.kept, "")?;
+            fs::hard_link(&self.kept, temporary)?;
+        }
+        self.sim.send_packet(data).await
+    }
+}
+
+// Short timeouts for a link that is known to fail
+async fn connect_unplugged(sim: &SimulatedCrazyflie, writes: usize) -> CFLoader<Bllink<Unplug>> {
+    let policy = RetryPolicy { retries: 1, timeout: Duration::from_millis(20), backoff: 1 };
+    let config = LinkConfig { info: policy, buffer_load: policy, flash_write: policy, flash_read: policy, ..LinkConfig::default() };
+    let link = Bllink::with_packet_link(Unplug { sim: sim.clone(), writes }).with_config(config);
+    CFLoader::new(link).await.unwrap()
+}
+
+// Flash an image with a checkpoint, unplugged after `writes` writes, then resume it and return
+// the number of writes of the resumed flashing
+async fn resume_after(writes: usize, image: &[u8], options: &FlashOptions) -> usize {
+    let sim = SimulatedCrazyflie::default();
+    let mut cfloader = connect_unplugged(&sim, writes).await;
+    assert!(cfloader.flash_image_with_options(TARGET, START_ADDRESS, image, options, None::<fn(usize, usize)>).await.is_err());
+    assert!(options.checkpoint.as_ref().unwrap().exists());
+
+    let before = sim.write_count(TARGET);
+    let mut cfloader = connect(sim.clone()).await;
+    cfloader.resume_flash(TARGET, START_ADDRESS, image, options, None::<fn(usize, usize)>).await.unwrap();
+
+    assert_eq!(flash_content(&sim, START_ADDRESS, image.len()), image);
+    assert!(!options.checkpoint.as_ref().unwrap().exists());
+    sim.write_count(TARGET) - before
+}
+
+fn checkpoint_options(name: &str) -> FlashOptions {
+    let path = std::env::temp_dir().join(format!("cfloader-{}-{}.checkpoint", name, std::process::id()));
+    FlashOptions { checkpoint: Some(path), ..Default::default() }
+}
+
+fn flash_content(sim: &SimulatedCrazyflie, address: u32, length: usize) -> Vec<u8> {
+    sim.flash(TARGET)[address as usize..address as usize + length].to_vec()
+}
+
+#[tokio::test]
+async fn flash_and_verify_unaligned_image() {
+    // The image starts 5 bytes into its first page and ends 919 bytes before the end of its
+    // last page, over a flash holding other data
+    let around = image(13 * 1024, 99);
+    let image = image(12 * 1024 + 100, 3);
+    let address = START_ADDRESS + 5;
+    let (head, tail) = (0..5, 5 + image.len()..13 * 1024);
+
+    for partial_pages in [PartialPage::Preserve, PartialPage::Erase] {
+        let sim = SimulatedCrazyflie::default();
+        sim.set_flash(TARGET, START_ADDRESS, &around);
+        let mut cfloader = connect(sim.clone()).await;
+        let options = FlashOptions { partial_pages, ..Default::default() };
+
+        let report = cfloader.flash_and_verify(TARGET, address, &image, &options, None::<fn(usize, usize)>).await.unwrap();
+
+        assert!(report.is_ok());
+        assert_eq!(report.bytes_checked, image.len());
+        assert!(report.repaired_pages.is_empty());
+        assert_eq!(flash_content(&sim, address, image.len()), image);
+        assert_eq!(cfloader.read_flash(TARGET, address, image.len() as u32).await.unwrap(), image);
+
+        let pages = flash_content(&sim, START_ADDRESS, 13 * 1024);
+        match partial_pages {
+            PartialPage::Preserve => {
+                assert_eq!(pages[head.clone()], around[head.clone()]);
+                assert_eq!(pages[tail.clone()], around[tail.clone()]);
+            }
+            PartialPage::Erase => {
+                assert!(pages[head.clone()].iter().all(|&byte| byte == 0xff));
+                assert!(pages[tail.clone()].iter().all(|&byte| byte == 0xff));
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn verify_reports_mismatched_ranges() {
+    let sim = SimulatedCrazyflie::default();
+    let mut cfloader = connect(sim.clone()).await;
+    let image = image(6 * 1024, 22);
+    cfloader.flash_image(TARGET, START_ADDRESS, &image).await.unwrap();
+
+    // Three bytes in page 17 and four bytes across the boundary of pages 19 and 20
+    sim.set_flash(TARGET, 17 * 1024 + 100, &[!image[1124], !image[1125], !image[1126]]);
+    sim.set_flash(TARGET, 20 * 1024 - 2, &[!image[4094], !image[4095], !image[4096], !image[4097]]);
+    let report = cfloader.verify(TARGET, START_ADDRESS, &image).await.unwrap();
+
+    assert_eq!(report.target, TARGET);
+    assert!(report.is_complete());
+    assert_eq!(report.mismatched_pages(), vec![17, 19, 20]);
+    assert_eq!(report.mismatched_bytes(), 7);
+    let mismatch = |page: u16, offset: u16, range: std::ops::Range<usize>| Mismatch {
+        page,
+        offset,
+        address: START_ADDRESS + range.start as u32,
+        expected: image[range.clone()].to_vec(),
+        actual: image[range].iter().map(|byte| !byte).collect(),
+    };
+    assert_eq!(report.mismatches, vec![mismatch(17, 100, 1124..1127), mismatch(19, 1022, 4094..4096), mismatch(20, 0, 4096..4098)]);
+}
+
+#[tokio::test]
+async fn failed_verify_step_reports_the_target() {
+    // The byte stays cleared in page 18, the pages after the first one are verified before it
+    let sim = SimulatedCrazyflie::default();
+    let image = image(12 * 1024, 23);
+    assert_ne!(image[2 * 1024 + 5], 0x00);
+    let mut cfloader = connect(DamageFlash { sim, address: 18 * 1024 + 5, stuck: true, damaged: false }).await;
+    let options = FlashOptions { safe_order: true, ..Default::default() };
+
+    let result = cfloader.flash_image_with_options(TARGET, START_ADDRESS, &image, &options, None::<fn(usize, usize)>).await;
+    assert!(matches!(&result, Err(Error::VerifyFailed { target: TARGET, pages }) if pages == &[18]), "{:?}", result);
+}
+
+#[tokio::test]
+async fn flash_and_verify_repairs_a_damaged_page() {
+    let sim = SimulatedCrazyflie::default();
+    let image = image(12 * 1024, 24);
+    let mut cfloader = connect(DamageFlash { sim: sim.clone(), address: 21 * 1024 + 9, stuck: false, damaged: false }).await;
+
+    let report = cfloader.flash_and_verify(TARGET, START_ADDRESS, &image, &FlashOptions::default(), None::<fn(usize, usize)>).await.unwrap();
+
+    assert!(report.is_ok());
+    assert_eq!(report.repaired_pages, vec![21]);
+    assert_eq!(flash_content(&sim, START_ADDRESS, image.len()), image);
+    // Two writes for the image and one for the repaired page
+    assert_eq!(sim.write_count(TARGET), 3);
+}
+
+#[tokio::test]
+async fn flash_and_verify_gives_up_on_a_stuck_page() {
+    let sim = SimulatedCrazyflie::default();
+    let image = image(12 * 1024, 25);
+    assert_ne!(image[5 * 1024 + 9], 0x00);
+    let mut cfloader = connect(DamageFlash { sim: sim.clone(), address: 21 * 1024 + 9, stuck: true, damaged: false }).await;
+    let options = FlashOptions { repair_attempts: 2, ..Default::default() };
+
+    let report = cfloader.flash_and_verify(TARGET, START_ADDRESS, &image, &options, None::<fn(usize, usize)>).await.unwrap();
+
+    // The page is written again on each attempt and still reported
+    assert_eq!(report.repaired_pages, vec![21]);
+    assert_eq!(report.mismatched_pages(), vec![21]);
+    assert_eq!(report.mismatches[0].address, 21 * 1024 + 9);
+    assert_eq!(sim.write_count(TARGET), 2 + 2);
+}
+
+#[tokio::test]
+async fn stuck_page_rolls_back_with_a_backup() {
+    let firmware = image(48 * 1024, 26);
+    let sim = sim_with_firmware(&firmware);
+    let image = image(12 * 1024, 27);
+    assert_ne!(image[5 * 1024 + 9], 0x00);
+    let mut cfloader = connect(DamageFlash { sim: sim.clone(), address: 21 * 1024 + 9, stuck: true, damaged: false }).await;
+    let options = FlashOptions { repair_attempts: 1, ..backup_options("repair-rollback") };
+
+    let result = cfloader.flash_and_verify(TARGET, START_ADDRESS, &image, &options, None::<fn(usize, usize)>).await;
+
+    let Err(Error::RolledBack { error, .. }) = result else {
+        panic!("{:?}", result);
+    };
+    assert!(matches!(&*error, Error::VerifyFailed { target: TARGET, pages } if pages == &[21]), "{}", error);
+    std::fs::remove_dir_all(options.backup.unwrap()).unwrap();
+}
+
+#[tokio::test]
+async fn read_flash_after_short_response() {
+    let sim = SimulatedCrazyflie::default();
+    let image = image(4 * 1024, 5);
+    sim.set_flash(TARGET, START_ADDRESS, &image);
+    // The two connection probes read flash first
+    let mut cfloader = connect(CutResponse { sim: sim.clone(), command: CMD_READ_FLASH, skip: 2, cut: 5 }).await;
+
+    assert_eq!(cfloader.payload_size(TARGET).unwrap(), PayloadSize::default());
+    assert_eq!(cfloader.read_flash(TARGET, START_ADDRESS, image.len() as u32).await.unwrap(), image);
+}
+
+#[tokio::test]
+async fn read_flash_fails_on_empty_response() {
+    // The two connection probes read flash first, the response to the first read carries no data
+    let sim = SimulatedCrazyflie::default();
+    let mut cfloader = connect(CutResponse { sim, command: CMD_READ_FLASH, skip: 2, cut: 25 }).await;
+
+    let result = cfloader.read_flash(TARGET, START_ADDRESS, 1024).await;
+    assert!(matches!(result, Err(Error::ShortRead { address: START_ADDRESS, missing: 1024, .. })), "{:?}", result);
+}
+
+#[tokio::test]
+async fn oversized_buffer_load_is_an_invalid_argument() {
+    let mut link = Bllink::with_packet_link(SimulatedCrazyflie::default());
+    let data = [0; 26];
+
+    let result = Bootloader::stm32().load_buffer(&mut link, 0, 0, &data).await;
+    assert!(matches!(result, Err(Error::InvalidArgument(_))), "{:?}", result);
+}
+
+#[tokio::test]
+async fn mapping_holds_only_the_sectors() {
+    let mut link = Bllink::with_packet_link(SimulatedCrazyflie::default());
+
+    assert_eq!(Bootloader::stm32().get_mapping(&mut link).await.unwrap(), vec![4, 16, 1, 64, 7, 128]);
+}
+
+// Load two buffer pages with pipelined LOAD_BUFFER packets, returns the data loaded
+async fn load_two_pages<P: PacketLink>(link: &mut Bllink<P>) -> Vec<u8> {
+    let data = image(2 * 1024, 30);
+    let segments: Vec<(u16, u16, &[u8])> = data
+        .chunks(1024)
+        .enumerate()
+        .flat_map(|(page, content)| content.chunks(25).enumerate().map(move |(i, chunk)| (page as u16, (i * 25) as u16, chunk)))
+        .collect();
+    assert_eq!(segments.len(), 82);
+    Bootloader::stm32().load_buffer_pipelined(link, &segments).await.unwrap();
+    data
+}
+
+#[tokio::test]
+async fn pipelined_loads_resend_only_lost_packets() {
+    // Packets are sent back-to-back without polling. The ones lost before reaching the
+    // bootloader are sent again, the others are received once.
+    let sim = SimulatedCrazyflie::default();
+    let config = FaultConfig { drop_packet: 0.3, ..FaultConfig::none(9) };
+    let mut link = Bllink::with_packet_link(FaultyLink::new(sim.clone(), config));
+
+    let data = load_two_pages(&mut link).await;
+
+    let stats = link.packet_link().stats().clone();
+    assert!(stats.dropped_packets > 0);
+    assert_eq!(sim.packet_count(), 82);
+    assert_eq!(stats.packets, 82 + stats.dropped_packets);
+    assert_eq!(&sim.buffer(TARGET)[..2048], &data[..]);
+}
+
+#[tokio::test]
+async fn pipelined_loads_under_ack_loss() {
+    // A packet whose acknowledgement is lost is received and sent again, loading it twice is harmless
+    let sim = SimulatedCrazyflie::default();
+    let config = FaultConfig { drop_ack: 0.3, ..FaultConfig::none(10) };
+    let mut link = Bllink::with_packet_link(FaultyLink::new(sim.clone(), config));
+
+    let data = load_two_pages(&mut link).await;
+
+    let stats = link.packet_link().stats().clone();
+    assert!(stats.dropped_acks > 0);
+    assert_eq!(sim.packet_count(), stats.packets);
+    assert_eq!(sim.packet_count(), 82 + stats.dropped_acks);
+    assert_eq!(&sim.buffer(TARGET)[..2048], &data[..]);
+}
+
+#[tokio::test]
+async fn pipelined_reads_count_only_acknowledged_attempts() {
+    // Each read is lost twice before reaching the bootloader, more than its retries. The lost
+    // sends are not attempts: the reads complete.
+    let image = image(2 * 1024, 31);
+    let reads: Vec<(u16, u16)> = (0..2048).step_by(28).map(|address| (16 + address / 1024, address % 1024)).collect();
+    let config = LinkConfig { flash_read: RetryPolicy { retries: 1, ..LinkConfig::default().flash_read }, ..LinkConfig::default() };
+
+    let sim = SimulatedCrazyflie::default();
+    sim.set_flash(TARGET, START_ADDRESS, &image);
+    let mut link = Bllink::with_packet_link(LoseRequests { sim, command: CMD_READ_FLASH, times: 2, lost: HashMap::new() }).with_config(config.clone());
+    let packets = Bootloader::stm32().read_flash_pipelined(&mut link, &reads).await.unwrap();
+    assert_eq!(link.packet_link().lost.len(), reads.len());
+    for (packet, &(page, address)) in packets.iter().zip(&reads) {
+        let offset = (page as usize - 16) * 1024 + address as usize;
+        assert!(image[offset..].starts_with(&packet.data[..packet.data.len().min(image.len() - offset)]));
+    }
+
+    // A target never reached is reported as such
+    let faults = FaultConfig { drop_packet: 1.0, ..FaultConfig::none(12) };
+    let mut link = Bllink::with_packet_link(FaultyLink::new(SimulatedCrazyflie::default(), faults)).with_config(config);
+    let result = Bootloader::stm32().read_flash_pipelined(&mut link, &reads).await;
+    assert!(matches!(result, Err(Error::NoAck { .. })));
+}
+
+#[tokio::test]
+async fn timing_is_measured_per_target_and_command_class() {
+    let mut link = Bllink::with_packet_link(SimulatedCrazyflie::default());
+    Bootloader::nrf51().get_info(&mut link).await.unwrap();
+    Bootloader::stm32().read_flash(&mut link, 16, 0).await.unwrap();
+
+    let nrf51_info = link.timing(bootloader::TARGET_NRF51, CommandClass::Info).unwrap();
+    assert!(nrf51_info.round_trip_time().is_some());
+    assert!(link.timing(TARGET, CommandClass::FlashRead).unwrap().round_trip_time().is_some());
+    // Nothing measured for the STM32 info queries nor the flash writes
+    assert!(link.timing(TARGET, CommandClass::Info).is_none());
+    assert!(link.timing(bootloader::TARGET_NRF51, CommandClass::FlashWrite).is_none());
+}
+
+#[tokio::test]
+async fn connect_after_cut_info_response() {
+    // The first GET_INFO response echoes the command but is too short to decode, it is asked again
+    let sim = SimulatedCrazyflie::default();
+    let mut cfloader = connect(CutResponse { sim: sim.clone(), command: CMD_GET_INFO, skip: 0, cut: 10 }).await;
+    let image = image(2 * 1024, 7);
+
+    cfloader.flash_image(TARGET, START_ADDRESS, &image).await.unwrap();
+    assert_eq!(flash_content(&sim, START_ADDRESS, image.len()), image);
+}
+
+#[tokio::test]
+async fn malformed_responses_use_the_attempts_of_the_policy() {
+    // The GET_INFO responses are lost or cut, the request is sent once per attempt of the info
+    // policy whether its response is lost or malformed
+    let policy = RetryPolicy { retries: 3, timeout: Duration::from_millis(20), backoff: 1 };
+    let link = LoseOrCutResponses { sim: SimulatedCrazyflie::default(), command: CMD_GET_INFO, cut: 10, requests: 0 };
+    let mut link = Bllink::with_packet_link(link).with_config(LinkConfig { info: policy, ..LinkConfig::default() });
+
+    let result = Bootloader::stm32().get_info(&mut link).await;
+
+    assert!(matches!(result, Err(Error::MalformedPacket(_))));
+    assert_eq!(link.packet_link().requests, 4);
+}
+
+#[tokio::test]
+async fn slow_write_of_a_full_buffer() {
+    // 10 pages written at once take 2.5s, longer than the 2s flash write timeout of one page
+    let stm32 = SimTargetConfig { page_write_time: Duration::from_millis(250), ..SimTargetConfig::stm32() };
+    let sim = SimulatedCrazyflie::new(SimTargetConfig::nrf51(), stm32);
+    let mut cfloader = connect(sim.clone()).await;
+    let image = image(10 * 1024, 6);
+
+    cfloader.flash_image(TARGET, START_ADDRESS, &image).await.unwrap();
+
+    assert_eq!(sim.write_count(TARGET), 1);
+    assert_eq!(flash_content(&sim, START_ADDRESS, image.len()), image);
+}
+
+#[tokio::test]
+async fn lost_write_acknowledgements_are_checked_without_info_requests() {
+    // The written pages are compared with the buffer, with the page size known since connecting
+    let sim = SimulatedCrazyflie::default();
+    let mut cfloader = connect(LoseWriteAcks { sim: sim.clone(), stuck: None, infos: 0 }).await;
+    let infos = cfloader.link().packet_link().infos;
+    let image = image(12 * 1024, 14);
+
+    cfloader.flash_image(TARGET, START_ADDRESS, &image).await.unwrap();
+
+    assert_eq!(sim.write_count(TARGET), 2);
+    assert_eq!(cfloader.link().packet_link().infos, infos);
+    assert_eq!(flash_content(&sim, START_ADDRESS, image.len()), image);
+}
+
+#[tokio::test]
+async fn write_never_matching_the_buffer_fails_verification() {
+    // The bootloader is idle after each write but a flash byte of the second page stays cleared
+    let sim = SimulatedCrazyflie::default();
+    let policy = RetryPolicy { retries: 2, ..LinkConfig::default().flash_write };
+    let link = LoseWriteAcks { sim: sim.clone(), stuck: Some(START_ADDRESS + 1024 + 7), infos: 0 };
+    let link = Bllink::with_packet_link(link).with_config(LinkConfig { flash_write: policy, ..LinkConfig::default() });
+    let mut cfloader = CFLoader::new(link).await.unwrap();
+
+    let result = cfloader.flash_image(TARGET, START_ADDRESS, &image(2 * 1024, 15)).await;
+
+    assert!(matches!(result, Err(Error::VerifyFailed { target: TARGET, pages }) if pages == [17]));
+    assert_eq!(sim.write_count(TARGET), 3);
+}
+
+#[tokio::test]
+async fn write_policy_retrying_forever() {
+    let sim = SimulatedCrazyflie::default();
+    let flash_write = RetryPolicy { retries: usize::MAX, timeout: Duration::MAX, backoff: 1 };
+    let link = Bllink::with_packet_link(sim.clone()).with_config(LinkConfig { flash_write, ..LinkConfig::default() });
+    let mut cfloader = CFLoader::new(link).await.unwrap();
+    let image = image(12 * 1024, 18);
+
+    cfloader.flash_image(TARGET, START_ADDRESS, &image).await.unwrap();
+    assert_eq!(flash_content(&sim, START_ADDRESS, image.len()), image);
+}
+
+#[tokio::test]
+async fn double_buffering_with_a_concurrent_writer() {
+    // The STM32 answers while writing, the next half of the buffer is loaded during each write
+    let stm32 = SimTargetConfig { page_write_time: Duration::from_millis(10), concurrent_write: true, ..SimTargetConfig::stm32() };
+    let sim = SimulatedCrazyflie::new(SimTargetConfig::nrf51(), stm32);
+    let mut cfloader = connect(OverlapLoads { sim: sim.clone(), writing: None, overlapped: 0 }).await;
+    let image = image(23 * 1024 + 70, 31);
+    let options = FlashOptions { double_buffer: true, ..Default::default() };
+    let plan = cfloader.plan_flash(TARGET, START_ADDRESS, &image, &options).await.unwrap();
+
+    let report = cfloader.flash_and_verify(TARGET, START_ADDRESS, &image, &options, None::<fn(usize, usize)>).await.unwrap();
+
+    assert!(report.is_ok());
+    assert!(report.repaired_pages.is_empty());
+    assert_eq!(plan.write_count(), 5);
+    assert_eq!(sim.write_count(TARGET), plan.write_count());
+    assert!(cfloader.link().packet_link().overlapped > 0);
+    assert_eq!(flash_content(&sim, START_ADDRESS, image.len()), image);
+}
+
+#[tokio::test]
+async fn platform_flashing_interleaves_slow_writes() {
+    let nrf51 = SimTargetConfig { page_write_time: Duration::from_millis(20), ..SimTargetConfig::nrf51() };
+    let stm32 = SimTargetConfig { page_write_time: Duration::from_millis(20), ..SimTargetConfig::stm32() };
+    let sim = SimulatedCrazyflie::new(nrf51, stm32);
+    let mut cfloader = connect(sim.clone()).await;
+    let stm32_image = image(25 * 1024 + 9, 16);
+    let nrf51_image = image(12 * 1024, 17);
+    let nrf51_address = 88 * 1024;
+
+    let mut progress = (0, 0);
+    cfloader
+        .flash_platform(START_ADDRESS, &stm32_image, nrf51_address, &nrf51_image, &FlashOptions::default(), Some(|done, total| progress = (done, total)))
+        .await
+        .unwrap();
+
+    assert_eq!(flash_content(&sim, START_ADDRESS, stm32_image.len()), stm32_image);
+    let nrf51_flash = sim.flash(bootloader::TARGET_NRF51);
+    assert_eq!(&nrf51_flash[nrf51_address as usize..nrf51_address as usize + nrf51_image.len()], &nrf51_image[..]);
+    assert_eq!((sim.write_count(TARGET), sim.write_count(bootloader::TARGET_NRF51)), (3, 2));
+    assert_eq!(progress, (stm32_image.len() + nrf51_image.len(), stm32_image.len() + nrf51_image.len()));
+}
+
+#[tokio::test]
+async fn lossy_link_flashes_or_fails_cleanly() {
+    for seed in 0..3 {
+        let sim = SimulatedCrazyflie::default();
+        let mut cfloader = connect(FaultyLink::new(sim.clone(), FaultConfig::packet_loss(seed, 0.3))).await;
+        let image = image(5 * 1024 + 7, seed as u8);
+
+        match cfloader.flash_image(TARGET, START_ADDRESS, &image).await {
+            Ok(()) => {
+                assert_eq!(flash_content(&sim, START_ADDRESS, image.len()), image, "seed {}", seed);
+                assert_eq!(cfloader.read_flash(TARGET, START_ADDRESS, image.len() as u32).await.unwrap(), image, "seed {}", seed);
+            }
+            Err(e) => assert!(matches!(e, Error::NoAck { .. } | Error::ResponseTimeout { .. }), "seed {}: {}", seed, e),
+        }
+    }
+}
+
+#[tokio::test]
+async fn stale_and_truncated_responses_flash_or_fail_cleanly() {
+    for seed in 0..6 {
+        let sim = SimulatedCrazyflie::default();
+        let config = FaultConfig { stale_response: 0.05, truncate: 0.05, ..FaultConfig::none(seed) };
+        let mut cfloader = connect(FaultyLink::new(sim.clone(), config)).await;
+        let image = image(4 * 1024 + 3, seed as u8);
+
+        match cfloader.flash_image(TARGET, START_ADDRESS, &image).await {
+            Ok(()) => assert_eq!(flash_content(&sim, START_ADDRESS, image.len()), image, "seed {}", seed),
+            // The partial last page is read before flashing, a response cut before its data ends the read
+            Err(e) => assert!(matches!(e, Error::NoAck { .. } | Error::ResponseTimeout { .. } | Error::ShortRead { .. }), "seed {}: {}", seed, e),
+        }
+    }
+}
+
+#[tokio::test]
+async fn read_flash_under_truncated_responses() {
+    let image = image(4 * 1024, 8);
+
+    for seed in 0..6 {
+        let sim = SimulatedCrazyflie::default();
+        sim.set_flash(TARGET, START_ADDRESS, &image);
+        let config = FaultConfig { truncate: 0.05, ..FaultConfig::none(seed) };
+        let mut cfloader = connect(FaultyLink::new(sim, config)).await;
+
+        // A cut response is never taken as flash content, one cut before its data ends the read
+        match cfloader.read_flash(TARGET, START_ADDRESS, image.len() as u32).await {
+            Ok(read) => assert_eq!(read, image, "seed {}", seed),
+            Err(e) => assert!(matches!(e, Error::NoAck { .. } | Error::ResponseTimeout { .. } | Error::ShortRead { .. }), "seed {}: {}", seed, e),
+        }
+    }
+}
+
+#[tokio::test]
+async fn latency_spikes_slow_down_flashing() {
+    for seed in 0..3 {
+        let sim = SimulatedCrazyflie::default();
+        let config = FaultConfig { latency_spike: 0.05, latency: Duration::from_millis(20), ..FaultConfig::none(seed) };
+        let mut cfloader = connect(FaultyLink::new(sim.clone(), config)).await;
+        let image = image(3 * 1024 + 1, seed as u8);
+
+        cfloader.flash_image(TARGET, START_ADDRESS, &image).await.unwrap_or_else(|e| panic!("seed {}: {}", seed, e));
+        assert_eq!(flash_content(&sim, START_ADDRESS, image.len()), image, "seed {}", seed);
+    }
+}
+
+#[tokio::test]
+async fn buffer_verify_reloads_corrupted_loads() {
+    let image = image(12 * 1024 + 40, 19);
+
+    for buffer_verify in [BufferVerify::Full, BufferVerify::Sampled(4)] {
+        let sim = SimulatedCrazyflie::default();
+        let config = FaultConfig { corrupt_load: 0.02, ..FaultConfig::none(7) };
+        let link = CountLoads { link: FaultyLink::new(sim.clone(), config), loads: 0 };
+        let mut cfloader = connect(link).await;
+        let options = FlashOptions { buffer_verify, ..Default::default() };
+        let plan = cfloader.plan_flash(TARGET, START_ADDRESS, &image, &options).await.unwrap();
+        let loads_before = cfloader.link().packet_link().loads;
+
+        cfloader.flash_image_with_options(TARGET, START_ADDRESS, &image, &options, None::<fn(usize, usize)>).await.unwrap();
+
+        // Each corrupted load is read back and loaded again before the write
+        let link = cfloader.link().packet_link();
+        assert!(link.link.stats().corrupted_loads > 0, "{:?}", buffer_verify);
+        assert!(link.loads - loads_before > plan.load_count(), "{:?}", buffer_verify);
+        assert_eq!(flash_content(&sim, START_ADDRESS, image.len()), image, "{:?}", buffer_verify);
+    }
+}
+
+#[tokio::test]
+async fn buffer_always_corrupted_is_never_written() {
+    let sim = SimulatedCrazyflie::default();
+    let config = FaultConfig { corrupt_load: 1.0, ..FaultConfig::none(8) };
+    let mut cfloader = connect(FaultyLink::new(sim.clone(), config)).await;
+    let options = FlashOptions { buffer_verify: BufferVerify::Full, ..Default::default() };
+
+    let result = cfloader.flash_image_with_options(TARGET, START_ADDRESS, &image(2 * 1024, 21), &options, None::<fn(usize, usize)>).await;
+    assert!(matches!(result, Err(Error::BufferMismatch { target: TARGET, page: 0, address: 0 })), "{:?}", result);
+    assert_eq!(sim.write_count(TARGET), 0);
+}
+
+#[tokio::test]
+async fn corrupted_loads_reach_the_flash_without_buffer_verify() {
+    let sim = SimulatedCrazyflie::default();
+    let config = FaultConfig { corrupt_load: 0.02, ..FaultConfig::none(7) };
+    let mut cfloader = connect(FaultyLink::new(sim.clone(), config)).await;
+    let image = image(12 * 1024 + 40, 19);
+
+    cfloader.flash_image(TARGET, START_ADDRESS, &image).await.unwrap();
+    assert_ne!(flash_content(&sim, START_ADDRESS, image.len()), image);
+}
+
+#[tokio::test]
+async fn delta_flashing_writes_only_the_changed_page() {
+    let sim = SimulatedCrazyflie::default();
+    let mut cfloader = connect(sim.clone()).await;
+    let image = image(25 * 1024 + 300, 28);
+    cfloader.flash_image(TARGET, START_ADDRESS, &image).await.unwrap();
+    let writes = sim.write_count(TARGET);
+
+    let mut update = image.clone();
+    update[13 * 1024 + 17] ^= 0x5a;
+    let options = FlashOptions { delta: true, ..Default::default() };
+    let plan = cfloader.plan_flash(TARGET, START_ADDRESS, &update, &options).await.unwrap();
+    assert_eq!(plan.writes().map(|write| (write.flash_page, write.n_pages)).collect::<Vec<_>>(), vec![(29, 1)]);
+
+    cfloader.flash_image_with_options(TARGET, START_ADDRESS, &update, &options, None::<fn(usize, usize)>).await.unwrap();
+    assert_eq!(sim.write_count(TARGET) - writes, 1);
+    assert_eq!(flash_content(&sim, START_ADDRESS, update.len()), update);
+}
+
+#[tokio::test]
+async fn resume_skips_the_checkpointed_writes() {
+    // 35 pages are written 10 at a time. Unplugged when the second write completes, before its
+    // response: the checkpoint holds the first write, the 25 pages after it take 3 writes.
+    let image = image(35 * 1024, 9);
+    assert_eq!(resume_after(2, &image, &checkpoint_options("resume-plain")).await, 3);
+}
+
+#[tokio::test]
+async fn resume_with_delta_skips_all_written_pages() {
+    // The flash is compared with the whole image, the second write is skipped too
+    let image = image(35 * 1024, 10);
+    let options = FlashOptions { delta: true, ..checkpoint_options("resume-delta") };
+    assert_eq!(resume_after(2, &image, &options).await, 2);
+}
+
+#[tokio::test]
+async fn resume_with_safe_order_writes_the_first_page_last() {
+    // The first page is erased then pages 1..11 are written before the checkpointed write is
+    // unplugged. The erased first page differs from the image: it is erased again, the 24
+    // pages after the checkpoint take 3 writes and the first page is written last.
+    let image = image(35 * 1024, 11);
+    let options = FlashOptions { safe_order: true, ..checkpoint_options("resume-safe-order") };
+    assert_eq!(resume_after(3, &image, &options).await, 5);
+}
+
+#[tokio::test]
+async fn safe_order_checkpoint_moves_forward() {
+    // The first page is written last, a flashing interrupted after it resumes without writing
+    let sim = SimulatedCrazyflie::default();
+    let image = image(35 * 1024, 13);
+    let options = FlashOptions { safe_order: true, ..checkpoint_options("safe-order-forward") };
+    let checkpoint = options.checkpoint.clone().unwrap();
+    let kept = checkpoint.with_extension("kept");
+    // Erase of the first page, 4 writes of the 34 pages after it and the first page
+    let link = KeepLastCheckpoint { sim: sim.clone(), checkpoint: checkpoint.clone(), kept: kept.clone(), writes: 6 };
+    let mut cfloader = connect(link).await;
+    cfloader.flash_image_with_options(TARGET, START_ADDRESS, &image, &options, None::<fn(usize, usize)>).await.unwrap();
+    assert_eq!(sim.write_count(TARGET), 6);
+
+    let last_page = (START_ADDRESS as usize + image.len()) / 1024 - 1;
+    assert_eq!(Checkpoint::load(&kept).unwrap().unwrap().last_page as usize, last_page);
+    fs::rename(&kept, &checkpoint).unwrap();
+    let mut cfloader = connect(sim.clone()).await;
+    cfloader.resume_flash(TARGET, START_ADDRESS, &image, &options, None::<fn(usize, usize)>).await.unwrap();
+    assert_eq!(sim.write_count(TARGET), 6);
+    assert_eq!(flash_content(&sim, START_ADDRESS, image.len()), image);
+}
+
+#[tokio::test]
+async fn resume_a_fully_committed_unaligned_image() {
+    // The checkpoint covers the last page, only partly filled by the image: its head is not
+    // written again on its own over the whole page
+    let sim = SimulatedCrazyflie::default();
+    let mut cfloader = connect(sim.clone()).await;
+    let image = image(1500, 12);
+    let options = checkpoint_options("resume-committed");
+    cfloader.flash_image_with_options(TARGET, START_ADDRESS, &image, &FlashOptions::default(), None::<fn(usize, usize)>).await.unwrap();
+    Checkpoint::new(TARGET, START_ADDRESS, &image, cfloader.stm32_info(), 17).save(options.checkpoint.as_ref().unwrap()).unwrap();
+
+    let writes = sim.write_count(TARGET);
+    cfloader.resume_flash(TARGET, START_ADDRESS, &image, &options, None::<fn(usize, usize)>).await.unwrap();
+    assert_eq!(sim.write_count(TARGET), writes);
+    assert_eq!(flash_content(&sim, START_ADDRESS, image.len()), image);
+    assert!(!options.checkpoint.as_ref().unwrap().exists());
+}
+
+// Simulator with a small flash holding a firmware, so that its backup is quick
+fn sim_with_firmware(firmware: &[u8]) -> SimulatedCrazyflie {
+    let stm32 = SimTargetConfig { n_flash_page: 64, ..SimTargetConfig::stm32() };
+    let sim = SimulatedCrazyflie::new(SimTargetConfig::nrf51(), stm32);
+    sim.set_flash(TARGET, START_ADDRESS, firmware);
+    sim
+}
+
+fn backup_options(name: &str) -> FlashOptions {
+    let dir = std::env::temp_dir().join(format!("cfloader-{}-{}", name, std::process::id()));
+    FlashOptions { backup: Some(dir), ..Default::default() }
+}
+
+#[tokio::test]
+async fn backups_in_the_same_second_are_kept() {
+    let firmware = image(48 * 1024, 29);
+    let sim = sim_with_firmware(&firmware);
+    let mut cfloader = connect(sim.clone()).await;
+    let dir = backup_options("backup-names").backup.unwrap();
+
+    let first = cfloader.backup(TARGET, &dir).await.unwrap();
+    sim.set_flash(TARGET, START_ADDRESS, &[0x00]);
+    let second = cfloader.backup(TARGET, &dir).await.unwrap();
+
+    assert_ne!(first, second);
+    assert_eq!(std::fs::read(&first).unwrap(), firmware);
+    assert_eq!(std::fs::read(&second).unwrap()[0], 0x00);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn failed_write_rolls_back() {
+    let firmware = image(48 * 1024, 12);
+    let sim = sim_with_firmware(&firmware);
+    let mut cfloader = connect(FailWrite { sim: sim.clone(), skip: 1 }).await;
+    let options = backup_options("rollback");
+
+    let result = cfloader.flash_image_with_options(TARGET, START_ADDRESS, &image(25 * 1024, 13), &options, None::<fn(usize, usize)>).await;
+
+    let Err(Error::RolledBack { error, backup }) = result else {
+        panic!("{:?}", result);
+    };
+    assert!(matches!(*error, Error::Flash { error: FlashError::AddressOutOfBounds, .. }), "{}", error);
+    assert_eq!(flash_content(&sim, START_ADDRESS, firmware.len()), firmware);
+    assert_eq!(std::fs::read(&backup).unwrap(), firmware);
+    std::fs::remove_dir_all(options.backup.unwrap()).unwrap();
+}
+
+#[tokio::test]
+async fn rollback_over_a_lost_link_fails() {
+    let firmware = image(48 * 1024, 14);
+    let sim = sim_with_firmware(&firmware);
+    let mut cfloader = connect_unplugged(&sim, 1).await;
+    let options = backup_options("rollback-failed");
+
+    let result = cfloader.flash_image_with_options(TARGET, START_ADDRESS, &image(25 * 1024, 15), &options, None::<fn(usize, usize)>).await;
+
+    // The backup is kept for a manual restore
+    let Err(Error::RollbackFailed { error, rollback, backup }) = result else {
+        panic!("{:?}", result);
+    };
+    assert!(matches!(*error, Error::NoAck { .. } | Error::ResponseTimeout { .. }), "{}", error);
+    assert!(matches!(*rollback, Error::NoAck { .. } | Error::ResponseTimeout { .. }), "{}", rollback);
+    assert_eq!(std::fs::read(&backup).unwrap(), firmware);
+    std::fs::remove_dir_all(options.backup.unwrap()).unwrap();
+}
+
+#[tokio::test]
+async fn write_count_under_ack_loss() {
+    // Erased pages alternating with data pages, the erased ones are written from one buffer page
+    let image: Vec<u8> = (0..35 * 1024).map(|i| if (i / 1024) % 2 == 0 { 0xff } else { (i / 1024) as u8 }).collect();
+    let options = FlashOptions { dedupe_pages: true, ..Default::default() };
+
+    for seed in 0..3 {
+        let sim = SimulatedCrazyflie::default();
+        let config = FaultConfig { drop_ack: 0.3, ..FaultConfig::none(seed) };
+        let mut cfloader = connect(FaultyLink::new(sim.clone(), config)).await;
+
+        cfloader.flash_image_with_options(TARGET, START_ADDRESS, &image, &options, None::<fn(usize, usize)>).await.unwrap();
+        let plan = cfloader.plan_flash(TARGET, START_ADDRESS, &image, &options).await.unwrap();
+
+        // A write whose acknowledgement is lost is never sent again
+        assert_eq!(flash_content(&sim, START_ADDRESS, image.len()), image, "seed {}", seed);
+        assert!(sim.write_count(TARGET) <= plan.write_count(), "seed {}: {} writes for a plan of {}", seed, sim.write_count(TARGET), plan.write_count());
+    }
+}
+
+#[tokio::test]
+async fn payload_size_probe_uses_larger_packets() {
+    let stm32 = SimTargetConfig { load_size: 50, read_size: 57, flash_read_size: 40, ..SimTargetConfig::stm32() };
+    let sim = SimulatedCrazyflie::new(SimTargetConfig::nrf51(), stm32).with_max_packet_size(64);
+    let mut cfloader = connect(sim.clone()).await;
+
+    assert_eq!(cfloader.payload_size(TARGET).unwrap(), PayloadSize { load: 50, read: 57, flash_read: 40 });
+    assert_eq!(cfloader.payload_size(bootloader::TARGET_NRF51).unwrap(), PayloadSize::default());
+
+    let image = image(3 * 1024 + 11, 4);
+    let report = cfloader.flash_and_verify(TARGET, START_ADDRESS, &image, &FlashOptions::default(), None::<fn(usize, usize)>).await.unwrap();
+    assert!(report.is_ok());
+    assert_eq!(flash_content(&sim, START_ADDRESS, image.len()), image);
+}
+
+#[tokio::test]
+async fn payload_size_of_an_old_bootloader_is_not_probed() {
+    let stm32 = SimTargetConfig { version: 0x01, load_size: 50, read_size: 57, flash_read_size: 40, ..SimTargetConfig::stm32() };
+    let sim = SimulatedCrazyflie::new(SimTargetConfig::nrf51(), stm32).with_max_packet_size(64);
+    let cfloader = connect(sim.clone()).await;
+
+    assert_eq!(cfloader.payload_size(TARGET).unwrap(), PayloadSize::default());
+    assert!(sim.buffer(TARGET).iter().all(|&byte| byte == 0xff));
+}
+
+#[tokio::test]
+async fn failed_payload_size_probe_uses_the_default() {
+    // The first flash read of the STM32 probe is answered without data
+    let stm32 = SimTargetConfig { load_size: 50, read_size: 57, flash_read_size: 40, ..SimTargetConfig::stm32() };
+    let sim = SimulatedCrazyflie::new(SimTargetConfig::nrf51(), stm32).with_max_packet_size(64);
+    let mut cfloader = connect(CutResponse { sim: sim.clone(), command: CMD_READ_FLASH, skip: 1, cut: 40 }).await;
+
+    assert_eq!(cfloader.payload_size(TARGET).unwrap(), PayloadSize::default());
+    let image = image(2 * 1024 + 3, 20);
+    cfloader.flash_image(TARGET, START_ADDRESS, &image).await.unwrap();
+    assert_eq!(flash_content(&sim, START_ADDRESS, image.len()), image);
+}
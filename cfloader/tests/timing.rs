@@ -0,0 +1,105 @@
+// Retry policies and link timing, computed without any link
+
+use std::time::Duration;
+
+use cfloader::timing::LinkTiming;
+use cfloader::RetryPolicy;
+
+#[test]
+fn total_timeout_saturates() {
+    let policy = RetryPolicy { retries: 9, timeout: Duration::from_secs(2), backoff: 1 };
+    assert_eq!(policy.total_timeout(), Duration::from_secs(20));
+
+    // Retrying forever waits at least as long as any policy that can be counted
+    let forever = RetryPolicy { retries: usize::MAX, timeout: Duration::from_secs(2), backoff: 1 };
+    assert_eq!(forever.total_timeout(), Duration::from_secs(2 * u32::MAX as u64));
+
+    let patient = RetryPolicy { retries: 3, timeout: Duration::MAX, backoff: 1 };
+    assert_eq!(patient.total_timeout(), Duration::MAX);
+}
+
+fn policy(timeout_ms: u64, backoff: u32) -> RetryPolicy {
+    RetryPolicy { retries: 10, timeout: Duration::from_millis(timeout_ms), backoff }
+}
+
+#[test]
+fn response_timeout_follows_the_round_trip_time() {
+    let mut timing = LinkTiming::default();
+    assert_eq!(timing.round_trip_time(), None);
+    assert_eq!(timing.response_timeout(&policy(100, 4)), Duration::from_millis(100));
+
+    // Smoothed round-trip time plus four times its variation, as RFC 6298
+    timing.record_round_trip(Duration::from_millis(10));
+    assert_eq!(timing.round_trip_time(), Some(Duration::from_millis(10)));
+    assert_eq!(timing.response_timeout(&policy(100, 4)), Duration::from_millis(30));
+    timing.record_round_trip(Duration::from_millis(10));
+    assert_eq!(timing.response_timeout(&policy(100, 4)), Duration::from_millis(25));
+    // Smoothed to 11ms with a variation of 4.8125ms
+    timing.record_round_trip(Duration::from_millis(18));
+    assert_eq!(timing.round_trip_time(), Some(Duration::from_millis(11)));
+    assert_eq!(timing.response_timeout(&policy(100, 4)), Duration::from_micros(30_250));
+
+    // Never longer than the policy timeout, never shorter than 5ms unless the policy is
+    assert_eq!(timing.response_timeout(&policy(20, 4)), Duration::from_millis(20));
+    let mut fast = LinkTiming::default();
+    fast.record_round_trip(Duration::from_micros(100));
+    assert_eq!(fast.response_timeout(&policy(100, 4)), Duration::from_millis(5));
+    assert_eq!(fast.response_timeout(&policy(2, 4)), Duration::from_millis(2));
+}
+
+#[test]
+fn backoff_grows_to_the_policy_limit_and_resets() {
+    let mut timing = LinkTiming::default();
+    timing.record_round_trip(Duration::from_millis(10));
+
+    timing.record_timeout();
+    assert_eq!(timing.response_timeout(&policy(100, 4)), Duration::from_millis(60));
+    timing.record_timeout();
+    assert_eq!(timing.response_timeout(&policy(100, 4)), Duration::from_millis(120));
+    timing.record_timeout();
+    assert_eq!(timing.response_timeout(&policy(100, 4)), Duration::from_millis(120));
+    assert_eq!(timing.response_timeout(&policy(100, 16)), Duration::from_millis(240));
+    // A backoff of 1 disables it
+    assert_eq!(timing.response_timeout(&policy(100, 1)), Duration::from_millis(30));
+
+    // A response in time resets the backoff
+    timing.record_round_trip(Duration::from_millis(10));
+    assert_eq!(timing.response_timeout(&policy(100, 4)), Duration::from_millis(25));
+}
+
+#[test]
+fn ack_rate_and_consecutive_losses() {
+    let mut timing = LinkTiming::default();
+    assert_eq!(timing.ack_rate(), 1.0);
+    assert_eq!(timing.retry_delay(), Duration::from_millis(1));
+    // Back-to-back polling while a quick response is expected on a clean link
+    assert_eq!(timing.poll_delay(Duration::ZERO), Duration::ZERO);
+
+    timing.record_ack(false);
+    assert_eq!(timing.ack_rate(), 1.0 - 1.0 / 16.0);
+    // The delay doubles after three consecutive losses, up to 32ms
+    let delays: Vec<u64> = (0..10)
+        .map(|_| {
+            timing.record_ack(false);
+            timing.retry_delay().as_millis() as u64
+        })
+        .collect();
+    assert_eq!(delays, vec![1, 1, 2, 4, 8, 16, 32, 32, 32, 32]);
+    assert_eq!(timing.poll_delay(Duration::ZERO), Duration::from_millis(32));
+    assert!(timing.ack_rate() < 0.5);
+
+    // An acknowledgement ends the losses, the rate only recovers slowly
+    timing.record_ack(true);
+    assert_eq!(timing.retry_delay(), Duration::from_millis(1));
+    assert!(timing.ack_rate() < 0.9);
+    assert_eq!(timing.poll_delay(Duration::ZERO), Duration::from_millis(1));
+    for _ in 0..64 {
+        timing.record_ack(true);
+    }
+    assert!(timing.ack_rate() > 0.9);
+    assert_eq!(timing.poll_delay(Duration::ZERO), Duration::ZERO);
+
+    // Long waits, such as flash writes, are polled less often
+    assert_eq!(timing.poll_delay(Duration::from_millis(8)), Duration::from_millis(2));
+    assert_eq!(timing.poll_delay(Duration::from_millis(100)), Duration::from_millis(4));
+}